@@ -0,0 +1,17 @@
+use std::io;
+
+use core;
+
+error_chain!{
+    foreign_links {
+        IOError(io::Error);
+        CoreError(core::Error);
+    }
+
+    errors {
+        InvalidPosition(text: String) {
+            description("invalid position")
+            display("invalid position '{}', expected seconds, mm:ss or hh:mm:ss", text)
+        }
+    }
+}
@@ -0,0 +1,221 @@
+#[macro_use]
+extern crate error_chain;
+extern crate clap;
+extern crate glib;
+extern crate termion;
+
+extern crate librebooks_core as core;
+
+mod errors;
+mod tui;
+
+use std::io::{self, Write};
+use std::sync::mpsc;
+use std::thread;
+use std::time;
+
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+
+use core::player::{self, Book, Command, Event, Metadata, Player, State};
+use core::uri;
+use errors::{ErrorKind, Result};
+
+quick_main!(run);
+
+fn run() -> Result<()> {
+    let book = || {
+        Arg::with_name("book")
+            .help("Audio file or folder of audio files, as a path or file:// URI")
+            .required(true)
+    };
+
+    let matches = App::new("librebooks")
+        .about("Listens to audiobooks from the terminal")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(
+            SubCommand::with_name("info")
+                .about("Shows title, author and length of a book")
+                .arg(book()),
+        )
+        .subcommand(
+            SubCommand::with_name("chapters")
+                .about("Lists the chapters of a book")
+                .arg(book()),
+        )
+        .subcommand(
+            SubCommand::with_name("play")
+                .about("Plays a book from where it was left")
+                .arg(book())
+                .arg(
+                    Arg::with_name("rate")
+                        .long("rate")
+                        .takes_value(true)
+                        .help("Playback speed, 1.0 being normal"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("seek")
+                .about("Plays a book from the given position")
+                .arg(book())
+                .arg(
+                    Arg::with_name("position")
+                        .required(true)
+                        .help("Seconds, mm:ss or hh:mm:ss"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("tui")
+                .about("Plays a book with keyboard controls")
+                .arg(book()),
+        )
+        .get_matches();
+
+    match matches.subcommand() {
+        ("info", Some(args)) => info(&open(args)?),
+        ("chapters", Some(args)) => chapters(&open(args)?),
+        ("play", Some(args)) => {
+            let rate = match args.value_of("rate") {
+                Some(rate) => Some(rate.parse().chain_err(|| "invalid rate")?),
+                None => None,
+            };
+            play(open(args)?, None, rate)
+        }
+        ("seek", Some(args)) => {
+            let position = parse_position(args.value_of("position").unwrap_or_default())?;
+            play(open(args)?, Some(position), None)
+        }
+        ("tui", Some(args)) => tui::run(open(args)?),
+        _ => Ok(()),
+    }
+}
+
+fn open(args: &ArgMatches) -> Result<Book> {
+    let path = uri::to_path(args.value_of("book").unwrap_or_default())?;
+    Ok(Book::open(&path)?)
+}
+
+fn info(book: &Book) -> Result<()> {
+    let metadata = &book.metadata;
+    println!("Title:    {}", metadata.title);
+    println!("Author:   {}", metadata.artist);
+    if !metadata.narrator.is_empty() {
+        println!("Narrator: {}", metadata.narrator);
+    }
+    println!("Album:    {}", metadata.album);
+    println!("Length:   {}", clock(metadata.duration));
+    println!("Chapters: {}", metadata.chapters.len());
+    println!("Files:    {}", book.parts.len());
+    Ok(())
+}
+
+fn chapters(book: &Book) -> Result<()> {
+    for (index, chapter) in book.metadata.chapters.iter().enumerate() {
+        println!(
+            "{:>3}  {}  {}  {}",
+            index + 1,
+            clock(chapter.start),
+            clock(chapter.end - chapter.start),
+            chapter.title
+        );
+    }
+    Ok(())
+}
+
+/// Plays until the end of the book, printing a progress line.
+fn play(book: Book, position: Option<time::Duration>, rate: Option<f64>) -> Result<()> {
+    let (player, events) = start_player()?;
+    let metadata = book.metadata.clone();
+
+    player.send(Command::OpenBook(book)).is_ok();
+    if let Some(position) = position {
+        player.send(Command::Seek(position)).is_ok();
+    }
+    if let Some(rate) = rate {
+        player.send(Command::SetRate(rate)).is_ok();
+    }
+    player.send(Command::Play).is_ok();
+
+    let mut started = false;
+    let mut rate = player::ask(&player, Command::Rate).unwrap_or(1.0);
+    let stdout = io::stdout();
+    while let Ok(event) = events.recv() {
+        match event {
+            Event::StateChanged(State::Playing) => started = true,
+            Event::StateChanged(State::Stopped) if started => break,
+            Event::EndOfStream => break,
+            Event::Error(error) => {
+                eprintln!("\n{}", error);
+                break;
+            }
+            Event::Warning(problem) => eprintln!("\r{}", problem),
+            Event::RateChanged(changed) => rate = changed,
+            Event::Progress(position) => {
+                let mut stdout = stdout.lock();
+                write!(stdout, "\r{}", status(&metadata, position, rate))?;
+                stdout.flush()?;
+            }
+            _ => {}
+        }
+    }
+
+    stop(&player);
+    println!();
+    Ok(())
+}
+
+/// Starts the player actor along with a GLib main loop on a thread of its
+/// own, which is where GStreamer delivers the player signals.
+fn start_player() -> Result<(mpsc::Sender<Command>, mpsc::Receiver<Event>)> {
+    core::init()?;
+    let (player, events) = Player::actor();
+    thread::spawn(|| glib::MainLoop::new(None, false).run());
+    Ok((player, events))
+}
+
+/// Ends the player actor, waiting until the position is saved.
+fn stop(player: &mpsc::Sender<Command>) {
+    player.send(Command::Stop).is_ok();
+    player::ask(player, Command::Position);
+    player.send(Command::Quit).is_ok();
+}
+
+/// One line with the position in the book and the current chapter.
+fn status(metadata: &Metadata, position: time::Duration, rate: f64) -> String {
+    let chapter = metadata
+        .chapters
+        .iter()
+        .find(|chapter| chapter.start <= position && position < chapter.end)
+        .map(|chapter| chapter.title.as_str())
+        .unwrap_or("");
+    format!(
+        "{} / {}  {}×  {}",
+        clock(position),
+        clock(metadata.duration),
+        rate,
+        chapter
+    )
+}
+
+fn clock(duration: time::Duration) -> String {
+    let seconds = duration.as_secs();
+    format!(
+        "{:02}:{:02}:{:02}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+fn parse_position(text: &str) -> Result<time::Duration> {
+    let mut seconds = 0;
+    for part in text.split(':') {
+        match part.trim().parse::<u64>() {
+            Ok(value) => seconds = seconds * 60 + value,
+            Err(_) => bail!(ErrorKind::InvalidPosition(text.to_string())),
+        }
+    }
+    if text.split(':').count() > 3 {
+        bail!(ErrorKind::InvalidPosition(text.to_string()));
+    }
+    Ok(time::Duration::from_secs(seconds))
+}
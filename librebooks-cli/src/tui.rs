@@ -0,0 +1,145 @@
+use std::io::{self, Write};
+use std::sync::mpsc;
+use std::thread;
+use std::time;
+
+use termion::clear;
+use termion::event::Key;
+use termion::input::TermRead;
+use termion::raw::IntoRawMode;
+
+use core::player::{self, Book, Command, Event, State};
+use core::Settings;
+use errors::Result;
+use {start_player, status, stop};
+
+const RATE_STEP: f64 = 0.25;
+
+/// Going to the previous chapter this far into a chapter restarts it
+/// instead.
+const RESTART_CHAPTER_SECS: u64 = 3;
+
+const HELP: &str = "space play/pause  ←/→ skip  p/n chapter  P/N part  -/+ speed  q quit";
+
+enum Input {
+    Key(Key),
+    Player(Event),
+}
+
+/// Plays a book with single-key controls and a live progress line, until
+/// the listener quits.
+pub fn run(book: Book) -> Result<()> {
+    let (player, events) = start_player()?;
+    let settings = Settings::open();
+    let metadata = book.metadata.clone();
+
+    player.send(Command::OpenBook(book)).is_ok();
+    player.send(Command::Play).is_ok();
+
+    let (tx, inputs) = mpsc::channel();
+    {
+        let tx = tx.clone();
+        thread::spawn(move || {
+            for key in io::stdin().keys() {
+                match key {
+                    Ok(key) => {
+                        if tx.send(Input::Key(key)).is_err() {
+                            break;
+                        }
+                    }
+                    Err(_) => break,
+                }
+            }
+        });
+    }
+    thread::spawn(move || {
+        while let Ok(event) = events.recv() {
+            if tx.send(Input::Player(event)).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut stdout = io::stdout().into_raw_mode()?;
+    write!(stdout, "{}\r\n{}\r\n", metadata.title, HELP)?;
+    if let Some(problem) = settings.problem() {
+        write!(stdout, "{}\r\n", problem)?;
+    }
+
+    let mut state = State::Stopped;
+    let mut position = time::Duration::from_secs(0);
+    let mut rate = player::ask(&player, Command::Rate).unwrap_or(1.0);
+
+    while let Ok(input) = inputs.recv() {
+        match input {
+            Input::Key(Key::Char('q')) | Input::Key(Key::Ctrl('c')) => break,
+            Input::Key(Key::Char(' ')) => {
+                let command = if state == State::Playing {
+                    Command::Pause
+                } else {
+                    Command::Play
+                };
+                player.send(command).is_ok();
+            }
+            Input::Key(Key::Left) => {
+                let target = position
+                    .checked_sub(settings.skip_backward())
+                    .unwrap_or(time::Duration::from_secs(0));
+                player.send(Command::Seek(target)).is_ok();
+            }
+            Input::Key(Key::Right) => {
+                let target = position + settings.skip_forward();
+                player.send(Command::Seek(target)).is_ok();
+            }
+            Input::Key(Key::Char(key @ 'n')) | Input::Key(Key::Char(key @ 'N')) => {
+                // Lower case skips the innermost chapters, upper case the
+                // outermost ones.
+                let level = if key == 'n' { metadata.depth() } else { 0 };
+                if let Some(start) = metadata.next_chapter(position, level) {
+                    player.send(Command::Seek(start)).is_ok();
+                }
+            }
+            Input::Key(Key::Char(key @ 'p')) | Input::Key(Key::Char(key @ 'P')) => {
+                let level = if key == 'p' { metadata.depth() } else { 0 };
+                let grace = time::Duration::from_secs(RESTART_CHAPTER_SECS);
+                if let Some(start) = metadata.previous_chapter(position, level, grace) {
+                    player.send(Command::Seek(start)).is_ok();
+                }
+            }
+            Input::Key(Key::Char('+')) | Input::Key(Key::Char('=')) => {
+                let faster = (rate + RATE_STEP).min(player::MAX_RATE);
+                player.send(Command::SetRate(faster)).is_ok();
+            }
+            Input::Key(Key::Char('-')) => {
+                let slower = (rate - RATE_STEP).max(player::MIN_RATE);
+                player.send(Command::SetRate(slower)).is_ok();
+            }
+            Input::Player(Event::StateChanged(changed)) => state = changed,
+            Input::Player(Event::Progress(changed)) => position = changed,
+            Input::Player(Event::RateChanged(changed)) => rate = changed,
+            Input::Player(Event::EndOfStream) => break,
+            Input::Player(Event::Error(error)) => {
+                write!(stdout, "\r\n{}", error)?;
+                break;
+            }
+            Input::Player(Event::Warning(problem)) => {
+                write!(stdout, "\r{}{}\r\n", clear::CurrentLine, problem)?
+            }
+            _ => continue,
+        }
+
+        let paused = if state == State::Playing { "" } else { "[paused] " };
+        write!(
+            stdout,
+            "\r{}{}{}",
+            clear::CurrentLine,
+            paused,
+            status(&metadata, position, rate)
+        )?;
+        stdout.flush()?;
+    }
+
+    stop(&player);
+    write!(stdout, "\r\n")?;
+    Ok(())
+}
@@ -0,0 +1,313 @@
+use std::cmp::Ordering;
+use std::fs;
+use std::io::Read;
+use std::path;
+use std::time;
+
+use errors::Result;
+use metadata::{nest_by_title, Chapter, Metadata};
+
+const IDENTITY_SAMPLE: u64 = 64 * 1024;
+
+pub const AUDIO_EXTENSIONS: &[&str] = &[
+    "aac", "flac", "m4a", "m4b", "mka", "mp3", "mp4", "oga", "ogg", "opus", "wav", "webm",
+];
+
+pub const AUDIO_MIME_TYPES: &[&str] = &[
+    "audio/mpeg",
+    "audio/mp4",
+    "audio/x-m4b",
+    "audio/flac",
+    "audio/x-flac",
+    "audio/ogg",
+    "audio/x-vorbis+ogg",
+    "audio/x-opus+ogg",
+    "audio/opus",
+    "audio/x-matroska",
+    "audio/webm",
+    "audio/aac",
+    "audio/x-wav",
+];
+
+#[derive(Debug, Clone)]
+pub struct Part {
+    pub metadata: Metadata,
+    pub offset: time::Duration,
+}
+
+/// One or more files played back to back on a single timeline. `metadata`
+/// describes the whole book, with chapters expressed in book time.
+#[derive(Debug, Clone)]
+pub struct Book {
+    pub id: String,
+    pub metadata: Metadata,
+    pub parts: Vec<Part>,
+}
+
+impl Book {
+    pub fn open(path: &path::Path) -> Result<Book> {
+        if path.is_dir() {
+            let mut files = vec![];
+            for entry in fs::read_dir(path)? {
+                let file = entry?.path();
+                if file.is_file() && is_audio(&file) {
+                    files.push(file);
+                }
+            }
+            files.sort_by(|a, b| natural_cmp(&file_name(a), &file_name(b)));
+            Book::from_files(files)
+        } else {
+            Book::from_files(vec![path.to_path_buf()])
+        }
+    }
+
+    pub fn from_files(files: Vec<path::PathBuf>) -> Result<Book> {
+        let mut metadata = vec![];
+        for file in files.iter() {
+            metadata.push(Metadata::from_file(file)?);
+        }
+        Book::from_metadata(metadata)
+    }
+
+    /// Assembles a book from already extracted metadata, one per file and
+    /// in playback order.
+    pub fn from_metadata(files: Vec<Metadata>) -> Result<Book> {
+        if files.is_empty() {
+            bail!("a book needs at least one audio file");
+        }
+
+        let paths: Vec<path::PathBuf> = files.iter().map(|file| file.path.clone()).collect();
+        let mut parts = vec![];
+        let mut chapters = vec![];
+        let mut offset = time::Duration::from_secs(0);
+
+        for (metadata, file) in files.into_iter().zip(paths.iter()) {
+            if metadata.chapters.is_empty() {
+                chapters.push(Chapter {
+                    title: if metadata.title.is_empty() {
+                        file_stem(file)
+                    } else {
+                        metadata.title.clone()
+                    },
+                    start: offset,
+                    end: offset + metadata.duration,
+                    artwork: None,
+                    parent: None,
+                    depth: 0,
+                });
+            } else {
+                // Parents are indices into the part's own chapters.
+                let base = chapters.len();
+                for chapter in metadata.chapters.iter() {
+                    chapters.push(Chapter {
+                        start: offset + chapter.start,
+                        end: offset + chapter.end,
+                        parent: chapter.parent.map(|parent| base + parent),
+                        ..chapter.clone()
+                    });
+                }
+            }
+
+            let duration = metadata.duration;
+            parts.push(Part { metadata, offset });
+            offset += duration;
+        }
+        // Tags rarely nest chapters, titles such as "Part One" often do.
+        nest_by_title(&mut chapters);
+
+        let metadata = {
+            let first = &parts[0].metadata;
+            let title = if parts.len() == 1 {
+                first.title.clone()
+            } else if !first.album.is_empty() {
+                first.album.clone()
+            } else {
+                paths[0]
+                    .parent()
+                    .map(|dir| file_name(dir))
+                    .unwrap_or_else(|| first.title.clone())
+            };
+
+            Metadata {
+                path: if parts.len() == 1 {
+                    first.path.clone()
+                } else {
+                    paths[0]
+                        .parent()
+                        .map(|dir| dir.to_path_buf())
+                        .unwrap_or_default()
+                },
+                title,
+                artist: first.artist.clone(),
+                album: first.album.clone(),
+                narrator: first.narrator.clone(),
+                duration: offset,
+                chapters,
+                artwork: parts
+                    .iter()
+                    .filter_map(|part| part.metadata.artwork.clone())
+                    .next(),
+            }
+        };
+
+        Ok(Book {
+            id: identity(&paths)?,
+            metadata,
+            parts,
+        })
+    }
+
+    /// Index of the part playing at `position` and the position inside it.
+    pub fn part_at(&self, position: time::Duration) -> Option<(usize, time::Duration)> {
+        let index = self
+            .parts
+            .iter()
+            .rposition(|part| part.offset <= position)?;
+        let part = &self.parts[index];
+        let inner = (position - part.offset).min(part.metadata.duration);
+        Some((index, inner))
+    }
+
+    /// Puts the length GStreamer measured for a part in place of the one it
+    /// was opened with, moving the parts and chapters after it along.
+    /// Returns whether anything changed.
+    pub fn correct_duration(&mut self, part: usize, duration: time::Duration) -> bool {
+        let (offset, estimated) = match self.parts.get(part) {
+            Some(part) => (part.offset, part.metadata.duration),
+            None => return false,
+        };
+        if duration == estimated {
+            return false;
+        }
+
+        // Times from the end of the part on move along with it, times inside
+        // the part are cut to its new length.
+        let (old_end, new_end) = (offset + estimated, offset + duration);
+        let moved = |time: time::Duration| {
+            if time >= old_end {
+                time - old_end + new_end
+            } else {
+                time.min(new_end)
+            }
+        };
+
+        self.parts[part].metadata.duration = duration;
+        let mut later_offset = new_end;
+        for later in self.parts.iter_mut().skip(part + 1) {
+            later.offset = later_offset;
+            later_offset += later.metadata.duration;
+        }
+        self.metadata.duration = later_offset;
+
+        // Parts of no length start where the part before ends, so chapters
+        // are told apart by the part they came from rather than by time.
+        let first = self.parts[..part].iter().map(chapters_in).sum::<usize>();
+        let after = first + chapters_in(&self.parts[part]);
+        for (index, chapter) in self.metadata.chapters.iter_mut().enumerate() {
+            if index >= after {
+                chapter.start = moved(chapter.start);
+            } else if index >= first {
+                chapter.start = chapter.start.min(new_end);
+            } else if chapter.end <= offset {
+                continue;
+            }
+            chapter.end = moved(chapter.end);
+        }
+        true
+    }
+}
+
+/// How many chapters a part adds to the book, one for the whole part when it
+/// has none of its own.
+fn chapters_in(part: &Part) -> usize {
+    part.metadata.chapters.len().max(1)
+}
+
+/// Hashes the size and leading bytes of every file, so a book keeps its
+/// identity when it is renamed or moved to another folder.
+fn identity(files: &[path::PathBuf]) -> Result<String> {
+    let mut hash = 0xcbf2_9ce4_8422_2325;
+
+    for file in files {
+        let size = fs::metadata(file)?.len();
+        hash = fnv1a(hash, size.to_string().as_bytes());
+
+        let mut sample = vec![];
+        fs::File::open(file)?
+            .take(IDENTITY_SAMPLE)
+            .read_to_end(&mut sample)?;
+        hash = fnv1a(hash, &sample);
+    }
+
+    Ok(format!("{:016x}", hash))
+}
+
+fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+pub fn is_audio(path: &path::Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| AUDIO_EXTENSIONS.contains(&extension.to_lowercase().as_str()))
+        .unwrap_or(false)
+}
+
+pub fn file_name(path: &path::Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+fn file_stem(path: &path::Path) -> String {
+    path.file_stem()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// Compares file names so that "2 - Intro.mp3" sorts before "10 - End.mp3".
+pub fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let mut a = a.chars().peekable();
+    let mut b = b.chars().peekable();
+
+    loop {
+        match (a.peek().cloned(), b.peek().cloned()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let x = take_number(&mut a);
+                let y = take_number(&mut b);
+                let ordering = x
+                    .len()
+                    .cmp(&y.len())
+                    .then_with(|| x.cmp(&y));
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+            }
+            (Some(x), Some(y)) => {
+                let ordering = x.to_lowercase().cmp(y.to_lowercase());
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+                a.next();
+                b.next();
+            }
+        }
+    }
+}
+
+fn take_number<I: Iterator<Item = char>>(chars: &mut ::std::iter::Peekable<I>) -> String {
+    let mut number = String::new();
+    while let Some(&c) = chars.peek() {
+        if !c.is_ascii_digit() {
+            break;
+        }
+        number.push(c);
+        chars.next();
+    }
+    number.trim_left_matches('0').to_string()
+}
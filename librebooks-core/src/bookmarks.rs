@@ -0,0 +1,119 @@
+use std::path;
+use std::time;
+
+use errors::{ErrorKind, Result};
+use store::{data_dir, read_json, recover_json, write_json};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Bookmark {
+    pub id: u64,
+    pub book: String,
+    pub position: time::Duration,
+    pub title: String,
+    pub note: String,
+    pub created: time::SystemTime,
+}
+
+/// Bookmarks of every book, kept as JSON in the user data dir. Every change
+/// is written through to disk right away.
+pub struct Bookmarks {
+    path: path::PathBuf,
+    bookmarks: Vec<Bookmark>,
+    /// Cleared when the file on disk could not be read nor moved aside.
+    writable: bool,
+    /// What went wrong reading the file, for the listener to see.
+    problem: Option<String>,
+}
+
+impl Bookmarks {
+    pub fn open() -> Bookmarks {
+        let path = data_dir().join("bookmarks.json");
+        let (bookmarks, problem) = recover_json(&path);
+        Bookmarks {
+            path,
+            writable: bookmarks.is_some(),
+            bookmarks: bookmarks.unwrap_or_default(),
+            problem: problem.map(|err| err.to_string()),
+        }
+    }
+
+    pub fn load(path: &path::Path) -> Result<Bookmarks> {
+        Ok(Bookmarks {
+            path: path.to_path_buf(),
+            bookmarks: read_json(path)?.unwrap_or_default(),
+            writable: true,
+            problem: None,
+        })
+    }
+
+    pub fn problem(&self) -> Option<&str> {
+        self.problem.as_ref().map(|problem| problem.as_str())
+    }
+
+    /// Bookmarks of a book, in playback order.
+    pub fn list(&self, book: &str) -> Vec<Bookmark> {
+        let mut bookmarks: Vec<Bookmark> = self
+            .bookmarks
+            .iter()
+            .filter(|bookmark| bookmark.book == book)
+            .cloned()
+            .collect();
+        bookmarks.sort_by_key(|bookmark| bookmark.position);
+        bookmarks
+    }
+
+    pub fn get(&self, id: u64) -> Option<&Bookmark> {
+        self.bookmarks.iter().find(|bookmark| bookmark.id == id)
+    }
+
+    pub fn create(&mut self, book: &str, position: time::Duration, title: &str) -> Result<Bookmark> {
+        let id = self
+            .bookmarks
+            .iter()
+            .map(|bookmark| bookmark.id + 1)
+            .max()
+            .unwrap_or(1);
+
+        let bookmark = Bookmark {
+            id,
+            book: book.to_string(),
+            position,
+            title: title.to_string(),
+            note: String::new(),
+            created: time::SystemTime::now(),
+        };
+        self.bookmarks.push(bookmark.clone());
+        self.save()?;
+        Ok(bookmark)
+    }
+
+    pub fn rename(&mut self, id: u64, title: &str) -> Result<()> {
+        self.find_mut(id)?.title = title.to_string();
+        self.save()
+    }
+
+    pub fn annotate(&mut self, id: u64, note: &str) -> Result<()> {
+        self.find_mut(id)?.note = note.to_string();
+        self.save()
+    }
+
+    pub fn delete(&mut self, id: u64) -> Result<()> {
+        self.find_mut(id)?;
+        self.bookmarks.retain(|bookmark| bookmark.id != id);
+        self.save()
+    }
+
+    fn find_mut(&mut self, id: u64) -> Result<&mut Bookmark> {
+        match self.bookmarks.iter_mut().find(|bookmark| bookmark.id == id) {
+            Some(bookmark) => Ok(bookmark),
+            None => bail!(ErrorKind::UnknownBookmark(id)),
+        }
+    }
+
+    fn save(&self) -> Result<()> {
+        if !self.writable {
+            bail!(ErrorKind::Protected(self.path.clone()));
+        }
+        write_json(&self.path, &self.bookmarks)
+    }
+}
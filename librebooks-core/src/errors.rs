@@ -1,8 +1,10 @@
 use std::io;
+use std::path::PathBuf;
 use std::sync::mpsc;
 
 use player;
 
+use dbus;
 use gst;
 use serde_json;
 
@@ -12,6 +14,42 @@ error_chain!{
         JsonError(serde_json::Error);
         IOError(io::Error);
         GTSError(gst::Error);
+        DBusError(dbus::Error);
         //PlayerError(mpsc::SendError<player::backend::Command>);
     }
+
+    errors {
+        MalformedMedia(format: &'static str, reason: String) {
+            description("malformed media file")
+            display("malformed {} file: {}", format, reason)
+        }
+        ProbeFailed(reason: String) {
+            description("ffprobe failed")
+            display("ffprobe failed: {}", reason)
+        }
+        NotLocal(uri: String) {
+            description("not a local file")
+            display("not a local file: {}", uri)
+        }
+        Protected(path: PathBuf) {
+            description("file left untouched")
+            display("not overwriting {}, it could not be read", path.display())
+        }
+        SetAside(path: PathBuf, aside: PathBuf, reason: String) {
+            description("unreadable file set aside")
+            display("could not read {}, moved it to {}: {}", path.display(), aside.display(), reason)
+        }
+        Unreadable(path: PathBuf, reason: String) {
+            description("unreadable file")
+            display("could not read {}, left it untouched: {}", path.display(), reason)
+        }
+        NewerSettings(found: u64, supported: u64) {
+            description("settings from a newer version")
+            display("settings version {} is newer than the supported {}", found, supported)
+        }
+        UnknownBookmark(id: u64) {
+            description("unknown bookmark")
+            display("unknown bookmark: {}", id)
+        }
+    }
 }
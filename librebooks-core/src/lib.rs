@@ -1,6 +1,8 @@
 #![feature(associated_type_defaults)]
 #![feature(duration_as_u128)]
 
+extern crate byteorder;
+extern crate dbus;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
@@ -8,6 +10,7 @@ extern crate serde_json;
 #[macro_use]
 extern crate error_chain;
 
+extern crate gio;
 extern crate glib;
 extern crate gstreamer as gst;
 
@@ -19,7 +22,28 @@ use std::thread;
 mod macros;
 
 mod metadata;
-pub use metadata::Metadata;
+pub use metadata::{Metadata, MetadataExtractor};
+
+mod book;
+pub use book::Book;
+
+mod store;
+
+mod bookmarks;
+pub use bookmarks::{Bookmark, Bookmarks};
+
+mod sleep;
+
+pub mod settings;
+pub use settings::Settings;
+
+pub mod mpris;
+
+pub mod library;
+
+pub mod player;
+
+pub mod uri;
 
 mod errors;
 pub use errors::Error;
@@ -55,90 +79,3 @@ pub fn init() -> Result<()> {
 }
 
 // let backend = backend::channel();
-
-pub mod player {
-    use glib::prelude::*;
-    use glib::Cast;
-    use glib::Value;
-    use gst;
-    use gst_player;
-    pub use gst_player::PlayerState as State;
-
-    use std::path;
-    use std::sync::mpsc;
-    use std::time;
-
-    pub use gst::ClockTime;
-    pub use metadata::{Chapter, Metadata};
-
-    #[derive(Debug)]
-    pub enum Event {
-        MetadataChanged(Metadata),
-        StateChanged(State),
-        Progress(time::Duration),
-    }
-
-    pub struct Player {
-        player: gst_player::Player,
-        events: mpsc::Sender<Event>,
-    }
-
-    impl Player {
-        pub fn new(events: mpsc::Sender<Event>) -> Player {
-            let dispatcher = gst_player::PlayerGMainContextSignalDispatcher::new(None);
-            let player = gst_player::Player::new(
-                None,
-                Some(&dispatcher.upcast::<gst_player::PlayerSignalDispatcher>()),
-            );
-
-            player.connect_end_of_stream(clone!(player => move |_| {
-                    player.stop();
-                }));
-
-            player.connect_error(clone!(player => move |_,_err| {
-                    player.stop();
-                }));
-
-            player.connect_state_changed(clone!(events => move |_, state| {
-                events.send(Event::StateChanged(state)).expect("delivered");
-            }));
-
-            player.connect_position_updated(clone!(events => move |_, position| {
-                if let Some(nanoseconds) = position.nanoseconds() {
-                    events.send(Event::Progress(time::Duration::from_nanos(nanoseconds))).expect("delivered");
-                }
-            }));
-
-            Player { player, events }
-        }
-
-        pub fn open(&self, path: path::PathBuf) {
-            let uri = {
-                let file = format!("file://{}", path.to_str().unwrap());
-                Value::from(&file)
-            };
-
-            if let Ok(metadata) = Metadata::from_file(&path) {
-                self.events
-                    .send(Event::MetadataChanged(metadata))
-                    .expect("delivered");
-                self.player.set_property("uri", &uri).is_ok();
-            }
-        }
-
-        pub fn seek(&self, position: time::Duration) {
-            self.player
-                .seek(ClockTime::from_nseconds(position.as_nanos() as u64));
-        }
-        pub fn play(&self) {
-            println!("Play");
-            self.player.play();
-        }
-
-        pub fn pause(&self) {
-            println!("Pause");
-            self.player.pause();
-        }
-    }
-
-}
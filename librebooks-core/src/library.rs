@@ -0,0 +1,285 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::path;
+use std::time;
+
+use glib;
+
+use book::{self, Book};
+use errors::Result;
+use metadata::{Artwork, Metadata};
+use store::{cache_dir, data_dir, read_json, write_json};
+
+/// A book found in the library folders.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    pub id: String,
+    #[serde(with = "::store::os_path::list")]
+    pub files: Vec<path::PathBuf>,
+    pub metadata: Metadata,
+    /// Cover image written out to the cache dir.
+    #[serde(default, with = "::store::os_path::option")]
+    pub cover: Option<path::PathBuf>,
+}
+
+impl Entry {
+    pub fn open(&self) -> Result<Book> {
+        Book::from_files(self.files.clone())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct File {
+    #[serde(with = "::store::os_path")]
+    path: path::PathBuf,
+    modified: time::SystemTime,
+    size: u64,
+    metadata: Metadata,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct Index {
+    files: Vec<File>,
+    books: Vec<Entry>,
+}
+
+#[derive(Debug, Default)]
+pub struct ScanReport {
+    pub files: usize,
+    pub extracted: usize,
+    pub removed: usize,
+    pub failed: Vec<(path::PathBuf, String)>,
+}
+
+/// Catalog of the books under the library folders. Metadata is cached per
+/// file along with its size and modification time, so a rescan only reads
+/// the files that changed.
+pub struct Library {
+    path: path::PathBuf,
+    index: Index,
+}
+
+impl Library {
+    pub fn open() -> Library {
+        let path = data_dir().join("library.json");
+        Library::load(&path).unwrap_or_else(|_| Library {
+            path,
+            index: Index::default(),
+        })
+    }
+
+    pub fn load(path: &path::Path) -> Result<Library> {
+        Ok(Library {
+            path: path.to_path_buf(),
+            index: read_json(path)?.unwrap_or_default(),
+        })
+    }
+
+    pub fn books(&self) -> &[Entry] {
+        &self.index.books
+    }
+
+    pub fn book(&self, id: &str) -> Option<&Entry> {
+        self.index.books.iter().find(|book| book.id == id)
+    }
+
+    pub fn scan(&mut self, folders: &[path::PathBuf]) -> Result<ScanReport> {
+        let mut report = ScanReport::default();
+
+        let mut found = vec![];
+        for folder in folders.iter().filter(|folder| folder.is_dir()) {
+            walk(folder, &mut found, &mut report.failed);
+        }
+        report.files = found.len();
+
+        let mut cache: HashMap<path::PathBuf, File> = self
+            .index
+            .files
+            .drain(..)
+            .map(|file| (file.path.clone(), file))
+            .collect();
+        let mut files = HashMap::new();
+        let mut changed = HashSet::new();
+        let mut artwork: HashMap<path::PathBuf, Artwork> = HashMap::new();
+        for path in found {
+            let stat = fs::metadata(&path).and_then(|stat| Ok((stat.modified()?, stat.len())));
+            let (modified, size) = match stat {
+                Ok(stat) => stat,
+                Err(err) => {
+                    report.failed.push((path, err.to_string()));
+                    continue;
+                }
+            };
+
+            let cached = cache
+                .remove(&path)
+                .filter(|file| file.modified == modified && file.size == size);
+            let file = match cached {
+                Some(file) => file,
+                None => match Metadata::from_file(&path) {
+                    Ok(mut metadata) => {
+                        // Pictures are read again when a book is opened, no
+                        // need to keep them around in the catalog.
+                        for chapter in metadata.chapters.iter_mut() {
+                            chapter.artwork = None;
+                        }
+                        if let Some(picture) = metadata.artwork.take() {
+                            artwork.insert(path.clone(), picture);
+                        }
+                        report.extracted += 1;
+                        changed.insert(path.clone());
+                        File {
+                            path: path.clone(),
+                            modified,
+                            size,
+                            metadata,
+                        }
+                    }
+                    Err(err) => {
+                        report.failed.push((path, err.to_string()));
+                        continue;
+                    }
+                },
+            };
+            files.insert(path, file);
+        }
+        report.removed = cache.len();
+
+        let mut books = vec![];
+        for group in group_books(&files) {
+            let reusable = !group.iter().any(|path| changed.contains(path));
+            if let Some(entry) = self
+                .index
+                .books
+                .iter()
+                .find(|book| reusable && book.files == group)
+            {
+                books.push(entry.clone());
+                continue;
+            }
+
+            let metadata = group
+                .iter()
+                .map(|path| files[path].metadata.clone())
+                .collect();
+            match Book::from_metadata(metadata) {
+                Ok(book) => {
+                    let cover = match group.iter().filter_map(|path| artwork.get(path)).next() {
+                        Some(picture) => store_cover(&book.id, picture),
+                        None => self
+                            .index
+                            .books
+                            .iter()
+                            .find(|book| book.files.iter().any(|path| group.contains(path)))
+                            .and_then(|book| book.cover.clone()),
+                    };
+                    books.push(Entry {
+                        id: book.id,
+                        files: group,
+                        metadata: book.metadata,
+                        cover,
+                    })
+                }
+                Err(err) => report.failed.push((group[0].clone(), err.to_string())),
+            }
+        }
+        self.index.books = books;
+        self.index.files = files.into_iter().map(|(_, file)| file).collect();
+        self.index.files.sort_by(|a, b| a.path.cmp(&b.path));
+
+        self.save()?;
+        Ok(report)
+    }
+
+    pub fn save(&self) -> Result<()> {
+        write_json(&self.path, &self.index)
+    }
+}
+
+fn store_cover(id: &str, artwork: &Artwork) -> Option<path::PathBuf> {
+    let extension = if artwork.mime == "image/png" { "png" } else { "jpg" };
+    let path = cache_dir()
+        .join("covers")
+        .join(format!("{}.{}", id, extension));
+
+    fs::create_dir_all(path.parent()?).ok()?;
+    fs::write(&path, &artwork.data).ok()?;
+    Some(path)
+}
+
+/// Folders scanned when none are configured.
+pub fn default_folders() -> Vec<path::PathBuf> {
+    let mut folders = vec![];
+    if let Some(home) = glib::get_home_dir() {
+        folders.push(home.join("Audiobooks"));
+    }
+    if let Some(music) = glib::get_user_special_dir(glib::UserDirectory::Music) {
+        folders.push(music);
+    }
+    folders
+}
+
+/// Collects the audio files under a folder. Files and folders that cannot
+/// be read are reported and skipped.
+fn walk(
+    dir: &path::Path,
+    found: &mut Vec<path::PathBuf>,
+    failed: &mut Vec<(path::PathBuf, String)>,
+) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) => {
+            failed.push((dir.to_path_buf(), err.to_string()));
+            return;
+        }
+    };
+
+    for entry in entries {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(err) => {
+                failed.push((dir.to_path_buf(), err.to_string()));
+                continue;
+            }
+        };
+        let path = entry.path();
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+
+        match entry.file_type() {
+            Ok(kind) if kind.is_dir() => walk(&path, found, failed),
+            Ok(_) => {
+                if path.is_file() && book::is_audio(&path) {
+                    found.push(path);
+                }
+            }
+            Err(err) => failed.push((path, err.to_string())),
+        }
+    }
+}
+
+/// Splits files into books: a file with chapters of its own is a book by
+/// itself, the remaining files of a folder make up one book together.
+fn group_books(files: &HashMap<path::PathBuf, File>) -> Vec<Vec<path::PathBuf>> {
+    let mut folders: BTreeMap<path::PathBuf, Vec<path::PathBuf>> = BTreeMap::new();
+    for path in files.keys() {
+        let folder = path.parent().map(|folder| folder.to_path_buf()).unwrap_or_default();
+        folders.entry(folder).or_insert_with(Vec::new).push(path.clone());
+    }
+
+    let mut groups = vec![];
+    for (_, mut paths) in folders {
+        paths.sort_by(|a, b| book::natural_cmp(&book::file_name(a), &book::file_name(b)));
+
+        let (standalone, rest): (Vec<path::PathBuf>, Vec<path::PathBuf>) = paths
+            .into_iter()
+            .partition(|path| !files[path].metadata.chapters.is_empty());
+
+        groups.extend(standalone.into_iter().map(|path| vec![path]));
+        if !rest.is_empty() {
+            groups.push(rest);
+        }
+    }
+    groups
+}
@@ -0,0 +1,421 @@
+use std::ffi::OsStr;
+use std::fs;
+use std::path;
+use std::time;
+
+use errors::{ErrorKind, Result};
+use metadata::{chapters_from_marks, Chapter, Metadata};
+
+/// CUE times count frames of CD audio, 75 to a second.
+const FRAMES_PER_SEC: u64 = 75;
+
+/// Embedded chapters starting this close to the tracks of a sheet are taken
+/// to be the same chapters.
+const SAME_START_MILLIS: u64 = 1000;
+
+fn malformed<S: Into<String>>(reason: S) -> ErrorKind {
+    ErrorKind::MalformedMedia("cue sheet", reason.into())
+}
+
+/// The parts of a CUE sheet that matter for chapters.
+#[derive(Debug, Default)]
+pub struct Sheet {
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    pub files: Vec<File>,
+}
+
+/// An audio file referenced by a sheet, with the tracks it holds.
+#[derive(Debug, Default)]
+pub struct File {
+    pub name: String,
+    pub tracks: Vec<Track>,
+}
+
+#[derive(Debug, Default)]
+pub struct Track {
+    pub number: u32,
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    pub start: Option<time::Duration>,
+}
+
+impl Sheet {
+    pub fn parse(text: &str) -> Result<Sheet> {
+        let mut sheet = Sheet::default();
+        // Track-level commands are told apart from sheet-level ones only by
+        // coming after a TRACK. A FILE line does not end the track, as rips
+        // put the pregap of a track in one file and its start in the next.
+        let mut in_track = false;
+
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim().trim_left_matches('\u{feff}');
+            let (command, rest) = match line.find(char::is_whitespace) {
+                Some(split) => (&line[..split], line[split..].trim()),
+                None => (line, ""),
+            };
+            let arguments = arguments(rest);
+            let first = arguments.get(0).cloned().unwrap_or_default();
+
+            match command.to_uppercase().as_str() {
+                "FILE" => {
+                    sheet.files.push(File {
+                        name: first,
+                        tracks: vec![],
+                    });
+                }
+                "TRACK" => {
+                    let number = first
+                        .parse()
+                        .map_err(|_| malformed(format!("bad track number on line {}", number + 1)))?;
+                    if sheet.files.is_empty() {
+                        sheet.files.push(File::default());
+                    }
+                    let file = sheet.files.last_mut().expect("a file");
+                    file.tracks.push(Track {
+                        number,
+                        ..Default::default()
+                    });
+                    in_track = true;
+                }
+                "TITLE" => match current_track(&mut sheet, in_track) {
+                    Some(track) => track.title = Some(first),
+                    None => sheet.title = Some(first),
+                },
+                "PERFORMER" => match current_track(&mut sheet, in_track) {
+                    Some(track) => track.performer = Some(first),
+                    None => sheet.performer = Some(first),
+                },
+                "INDEX" => {
+                    let index: u32 = first.parse().unwrap_or(1);
+                    let time = arguments.get(1).and_then(|time| parse_time(time)).ok_or_else(
+                        || malformed(format!("bad index time on line {}", number + 1)),
+                    )?;
+                    if index == 1 && in_track {
+                        carry_over(&mut sheet);
+                    }
+                    if let Some(track) = current_track(&mut sheet, in_track) {
+                        // Index 1 is where the track proper starts, index 0
+                        // only marks a pregap.
+                        if index == 1 || track.start.is_none() {
+                            track.start = Some(time);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(sheet)
+    }
+
+    /// Reads a sheet, which is often not in UTF-8 but in Latin-1.
+    pub fn open(path: &path::Path) -> Result<Sheet> {
+        let data = fs::read(path)?;
+        let text = match String::from_utf8(data) {
+            Ok(text) => text,
+            Err(err) => err.into_bytes().iter().map(|&byte| byte as char).collect(),
+        };
+        Sheet::parse(&text)
+    }
+
+    /// Index of the entry for the given audio file. Sheets made for a WAV
+    /// or APE rip still hold once the rip is transcoded, so names are
+    /// compared without extension when nothing matches exactly.
+    pub fn file_for(&self, audio: &path::Path) -> Option<usize> {
+        let name = audio.file_name()?;
+        let stem = audio.file_stem()?;
+        let names: Vec<&path::Path> = self
+            .files
+            .iter()
+            .map(|file| path::Path::new(&file.name))
+            .collect();
+
+        names
+            .iter()
+            .position(|file| file.file_name().map_or(false, |file| eq_ignore_case(file, name)))
+            .or_else(|| {
+                names.iter().position(|file| {
+                    file.file_stem()
+                        .map_or(false, |file| eq_ignore_case(file, stem))
+                })
+            })
+    }
+
+    /// Gives the file the tracks of the sheet as chapters, and fills in the
+    /// title and performer where the tags have none. Embedded chapters that
+    /// start where the tracks do are kept and only get the titles they lack,
+    /// other embedded chapters are replaced.
+    pub fn apply(&self, metadata: &mut Metadata, file: usize) {
+        let tracks: Vec<&Track> = match self.files.get(file) {
+            Some(file) => file.tracks.iter().filter(|track| track.start.is_some()).collect(),
+            None => return,
+        };
+
+        if !tracks.is_empty() && same_starts(&metadata.chapters, &tracks) {
+            for (chapter, track) in metadata.chapters.iter_mut().zip(tracks.iter()) {
+                match track.title {
+                    Some(ref title) if is_placeholder(&chapter.title) => {
+                        chapter.title = title.clone()
+                    }
+                    _ => {}
+                }
+            }
+        } else if !tracks.is_empty() {
+            let marks: Vec<(String, time::Duration)> = tracks
+                .iter()
+                .filter_map(|track| {
+                    let title = track
+                        .title
+                        .clone()
+                        .unwrap_or_else(|| format!("Track {}", track.number));
+                    track.start.map(|start| (title, start))
+                })
+                .collect();
+            metadata.chapters = chapters_from_marks(marks, metadata.duration);
+        }
+
+        if metadata.album.is_empty() {
+            metadata.album = self.title.clone().unwrap_or_default();
+        }
+        if metadata.title.is_empty() {
+            metadata.title = self.title.clone().unwrap_or_default();
+        }
+        if metadata.artist.is_empty() {
+            metadata.artist = self.performer.clone().unwrap_or_default();
+        }
+    }
+}
+
+/// Finds the CUE sheet that goes with an audio file, and which of its files
+/// that is. Sheets named after the file, as `book.cue` or `book.flac.cue`,
+/// come first, then any other sheet in the same folder that lists it.
+pub fn sidecar(audio: &path::Path) -> Option<(Sheet, usize)> {
+    let folder = match audio.parent() {
+        Some(folder) if !folder.as_os_str().is_empty() => folder,
+        _ => path::Path::new("."),
+    };
+    let mut appended = audio.as_os_str().to_owned();
+    appended.push(".cue");
+    let mut candidates = vec![audio.with_extension("cue"), path::PathBuf::from(appended)];
+    let mut others: Vec<path::PathBuf> = fs::read_dir(folder)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.extension()
+                .map_or(false, |extension| eq_ignore_case(extension, OsStr::new("cue")))
+        })
+        .filter(|path| !candidates.contains(path))
+        .collect();
+    others.sort();
+    candidates.extend(others);
+
+    for (rank, candidate) in candidates.iter().enumerate() {
+        if !candidate.is_file() {
+            continue;
+        }
+        let sheet = match Sheet::open(candidate) {
+            Ok(sheet) => sheet,
+            Err(_) => continue,
+        };
+        // A sheet named after the audio file may list it under any name.
+        let named_after = rank < 2 && sheet.files.len() == 1;
+        let file = match sheet.file_for(audio) {
+            Some(file) => file,
+            None if named_after => 0,
+            None => continue,
+        };
+        if !sheet.files[file].tracks.is_empty() {
+            return Some((sheet, file));
+        }
+    }
+    None
+}
+
+/// Splits the arguments of a command, keeping quoted strings whole.
+fn arguments(text: &str) -> Vec<String> {
+    let mut arguments = vec![];
+    let mut current = String::new();
+    let mut quoted = false;
+    for c in text.chars() {
+        match c {
+            '"' => quoted = !quoted,
+            c if c.is_whitespace() && !quoted => {
+                if !current.is_empty() {
+                    arguments.push(current);
+                    current = String::new();
+                }
+            }
+            c => current.push(c),
+        }
+    }
+    if !current.is_empty() {
+        arguments.push(current);
+    }
+    arguments
+}
+
+/// The track commands apply to, which after a FILE line is still the last
+/// track of an earlier file.
+fn current_track(sheet: &mut Sheet, in_track: bool) -> Option<&mut Track> {
+    if !in_track {
+        return None;
+    }
+    sheet
+        .files
+        .iter_mut()
+        .rev()
+        .filter_map(|file| file.tracks.last_mut())
+        .next()
+}
+
+/// Moves a track that began in an earlier file over to the file where its
+/// INDEX 01 is, leaving what came before as a pregap of the earlier file.
+fn carry_over(sheet: &mut Sheet) {
+    let last = match sheet.files.len() {
+        0 => return,
+        len => len - 1,
+    };
+    if !sheet.files[last].tracks.is_empty() {
+        return;
+    }
+    let earlier = sheet.files[..last]
+        .iter()
+        .rposition(|file| !file.tracks.is_empty());
+    if let Some(earlier) = earlier {
+        let mut track = sheet.files[earlier].tracks.pop().expect("a track");
+        track.start = None;
+        sheet.files[last].tracks.push(track);
+    }
+}
+
+fn same_starts(chapters: &[Chapter], tracks: &[&Track]) -> bool {
+    let tolerance = time::Duration::from_millis(SAME_START_MILLIS);
+    chapters.len() == tracks.len()
+        && chapters.iter().zip(tracks.iter()).all(|(chapter, track)| {
+            let start = track.start.unwrap_or_default();
+            let (early, late) = if chapter.start < start {
+                (chapter.start, start)
+            } else {
+                (start, chapter.start)
+            };
+            late - early <= tolerance
+        })
+}
+
+/// Titles that only number a chapter, which a sheet has better ones for.
+fn is_placeholder(title: &str) -> bool {
+    let title = title.trim().to_lowercase();
+    let number = title
+        .trim_left_matches("chapter")
+        .trim_left_matches("track")
+        .trim();
+    number.bytes().all(|c| c.is_ascii_digit())
+}
+
+/// Parses `MM:SS:FF`, minutes going past 59 on long rips.
+fn parse_time(text: &str) -> Option<time::Duration> {
+    let fields: Vec<u64> = text
+        .split(':')
+        .map(|field| field.parse().ok())
+        .collect::<Option<Vec<u64>>>()?;
+    if fields.len() != 3 || fields[1] >= 60 || fields[2] >= FRAMES_PER_SEC {
+        return None;
+    }
+    let seconds = fields[0].checked_mul(60)?.checked_add(fields[1])?;
+    let nanos = fields[2] * 1_000_000_000 / FRAMES_PER_SEC;
+    Some(time::Duration::new(seconds, nanos as u32))
+}
+
+fn eq_ignore_case(a: &OsStr, b: &OsStr) -> bool {
+    a.to_string_lossy().to_lowercase() == b.to_string_lossy().to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path;
+    use std::time;
+
+    use super::{parse_time, Sheet};
+    use metadata::{Chapter, Metadata};
+
+    /// Exact Audio Copy's default layout, the pregap of a track at the end
+    /// of the file before it.
+    const MULTI_FILE: &str = "TITLE \"Book\"\r
+FILE \"01.wav\" WAVE\r
+  TRACK 01 AUDIO\r
+    TITLE \"One\"\r
+    INDEX 01 00:00:00\r
+  TRACK 02 AUDIO\r
+    TITLE \"Two\"\r
+    INDEX 00 04:10:20\r
+FILE \"02.wav\" WAVE\r
+    INDEX 01 00:00:00\r
+  TRACK 03 AUDIO\r
+    TITLE \"Three\"\r
+    INDEX 01 03:00:00\r
+";
+
+    fn chapter(title: &str, start: u64, end: u64) -> Chapter {
+        Chapter {
+            title: title.to_string(),
+            start: time::Duration::from_secs(start),
+            end: time::Duration::from_secs(end),
+            artwork: None,
+            parent: None,
+            depth: 0,
+        }
+    }
+
+    #[test]
+    fn parses_long_times_and_rejects_overflow() {
+        assert_eq!(parse_time("125:30:37"), Some(time::Duration::new(7530, 493_333_333)));
+        assert_eq!(parse_time("307445734561825861:00:00"), None);
+        assert_eq!(parse_time("00:60:00"), None);
+    }
+
+    #[test]
+    fn moves_tracks_to_the_file_they_start_in() {
+        let sheet = Sheet::parse(MULTI_FILE).unwrap();
+        let first = sheet.file_for(path::Path::new("01.flac")).unwrap();
+        let second = sheet.file_for(path::Path::new("02.flac")).unwrap();
+
+        let mut metadata = Metadata::empty(&path::PathBuf::from("01.flac"));
+        metadata.duration = time::Duration::from_secs(251);
+        sheet.apply(&mut metadata, first);
+        assert_eq!(metadata.chapters.len(), 1);
+        assert_eq!(metadata.chapters[0].end, time::Duration::from_secs(251));
+
+        let mut metadata = Metadata::empty(&path::PathBuf::from("02.flac"));
+        metadata.duration = time::Duration::from_secs(400);
+        sheet.apply(&mut metadata, second);
+        let titles: Vec<&str> = metadata.chapters.iter().map(|chapter| chapter.title.as_str()).collect();
+        assert_eq!(titles, vec!["Two", "Three"]);
+        assert_eq!(metadata.chapters[0].start, time::Duration::from_secs(0));
+        assert_eq!(metadata.chapters[1].start, time::Duration::from_secs(180));
+    }
+
+    #[test]
+    fn supplements_matching_chapters() {
+        let sheet = Sheet::parse(MULTI_FILE).unwrap();
+        let mut metadata = Metadata::empty(&path::PathBuf::from("02.flac"));
+        metadata.chapters = vec![chapter("Chapter 1", 0, 180), chapter("Kept", 180, 400)];
+        metadata.chapters[1].depth = 1;
+        metadata.chapters[1].parent = Some(0);
+        sheet.apply(&mut metadata, 1);
+        assert_eq!(metadata.chapters[0].title, "Two");
+        assert_eq!(metadata.chapters[1].title, "Kept");
+        assert_eq!(metadata.chapters[1].parent, Some(0));
+    }
+
+    #[test]
+    fn overrides_other_chapters() {
+        let sheet = Sheet::parse(MULTI_FILE).unwrap();
+        let mut metadata = Metadata::empty(&path::PathBuf::from("02.flac"));
+        metadata.duration = time::Duration::from_secs(400);
+        metadata.chapters = vec![chapter("Whole file", 0, 400)];
+        sheet.apply(&mut metadata, 1);
+        assert_eq!(metadata.chapters.len(), 2);
+        assert_eq!(metadata.chapters[1].title, "Three");
+    }
+}
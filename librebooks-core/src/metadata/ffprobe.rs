@@ -0,0 +1,334 @@
+use std::collections::HashMap;
+use std::path;
+use std::process;
+use std::time;
+
+use serde_json;
+
+use errors::{ErrorKind, Result};
+use metadata::{Chapter, Metadata, MetadataExtractor};
+
+/// What ffprobe prints for values it could not determine.
+const NOT_AVAILABLE: &str = "N/A";
+
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+mod inner {
+    use std::collections::HashMap;
+
+    #[derive(Debug, Clone, Deserialize)]
+    pub struct Chapter {
+        #[serde(default)]
+        pub tags: HashMap<String, String>,
+        pub time_base: Option<String>,
+        pub start: Option<i64>,
+        pub end: Option<i64>,
+        pub start_time: Option<String>,
+        pub end_time: Option<String>,
+    }
+
+    #[derive(Debug, Clone, Default, Deserialize)]
+    pub struct Format {
+        #[serde(default)]
+        pub tags: HashMap<String, String>,
+        pub duration: Option<String>,
+    }
+
+    #[derive(Debug, Clone, Deserialize)]
+    pub struct Output {
+        #[serde(default)]
+        pub chapters: Vec<Chapter>,
+        #[serde(default)]
+        pub format: Format,
+    }
+}
+
+fn malformed<S: Into<String>>(reason: S) -> ErrorKind {
+    ErrorKind::MalformedMedia("ffprobe", reason.into())
+}
+
+/// Asks ffprobe for the tags and chapters of any file it understands.
+pub struct Ffprobe;
+
+impl MetadataExtractor for Ffprobe {
+    fn sniff(&self, _: &[u8]) -> bool {
+        true
+    }
+
+    fn extract(&self, path: &path::PathBuf) -> Result<Metadata> {
+        read(path)
+    }
+}
+
+fn read(path: &path::PathBuf) -> Result<Metadata> {
+    let output = process::Command::new("ffprobe")
+        .arg("-v")
+        .arg("quiet")
+        .arg("-print_format")
+        .arg("json")
+        .arg("-show_chapters")
+        .arg("-show_format")
+        .arg(path)
+        .output()
+        .map_err(|err| ErrorKind::ProbeFailed(err.to_string()))?;
+
+    if !output.status.success() {
+        bail!(ErrorKind::ProbeFailed(format!(
+            "{} on {}",
+            output.status,
+            path.display()
+        )));
+    }
+    parse(path, &output.stdout)
+}
+
+/// Builds the metadata out of ffprobe's JSON. Tags, durations and chapter
+/// times may all be missing.
+pub fn parse(path: &path::PathBuf, json: &[u8]) -> Result<Metadata> {
+    let output: inner::Output =
+        serde_json::from_slice(json).map_err(|err| malformed(err.to_string()))?;
+
+    let mut marks = Vec::with_capacity(output.chapters.len());
+    for (index, chapter) in output.chapters.iter().enumerate() {
+        let start = match chapter_time(chapter.start, &chapter.start_time, &chapter.time_base)? {
+            Some(start) => start,
+            None => bail!(malformed(format!("chapter {} has no start", index + 1))),
+        };
+        let end = chapter_time(chapter.end, &chapter.end_time, &chapter.time_base)?;
+        marks.push((tag(&chapter.tags, "title").unwrap_or_default(), start, end));
+    }
+    marks.sort_by_key(|&(_, start, _)| start);
+
+    let last_end = marks.iter().filter_map(|&(_, _, end)| end).max();
+    let measured = match output.format.duration {
+        Some(ref text) => parse_decimal(text)?,
+        None => None,
+    };
+    let duration = measured
+        .or(last_end)
+        .unwrap_or(time::Duration::from_secs(0));
+
+    let starts: Vec<time::Duration> = marks.iter().map(|&(_, start, _)| start).collect();
+    let chapters = marks
+        .into_iter()
+        .enumerate()
+        .map(|(index, (title, start, end))| {
+            let end = end
+                .or_else(|| starts.get(index + 1).cloned())
+                .unwrap_or(duration);
+            Chapter {
+                title,
+                start,
+                end: end.max(start),
+                artwork: None,
+                parent: None,
+                depth: 0,
+            }
+        })
+        .collect();
+
+    let tags = &output.format.tags;
+    Ok(Metadata {
+        path: path.clone(),
+        title: tag(tags, "title").unwrap_or_default(),
+        artist: tag(tags, "artist")
+            .or_else(|| tag(tags, "album_artist"))
+            .unwrap_or_default(),
+        album: tag(tags, "album").unwrap_or_default(),
+        narrator: tag(tags, "composer").unwrap_or_default(),
+        duration,
+        chapters,
+        artwork: None,
+    })
+}
+
+/// Containers differ in the case of their tag names, `title` in one is
+/// `TITLE` in the next.
+fn tag(tags: &HashMap<String, String>, name: &str) -> Option<String> {
+    tags.iter()
+        .find(|&(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+}
+
+/// Chapter times come both as a count of `time_base` units and as rounded
+/// seconds. The exact count is preferred.
+fn chapter_time(
+    units: Option<i64>,
+    text: &Option<String>,
+    time_base: &Option<String>,
+) -> Result<Option<time::Duration>> {
+    if let (Some(units), &Some(ref time_base)) = (units, time_base) {
+        return Ok(Some(rational_time(units, time_base)?));
+    }
+    match *text {
+        Some(ref text) => parse_decimal(text),
+        None => Ok(None),
+    }
+}
+
+/// Converts `units` of a `num/den` time base without going through floating
+/// point. Negative times, which ffprobe reports for some broken files, are
+/// clamped to zero, times too large for a `Duration` are rejected.
+pub fn rational_time(units: i64, time_base: &str) -> Result<time::Duration> {
+    let mut parts = time_base.trim().splitn(2, '/');
+    let numerator = parts.next().and_then(|part| part.parse::<u64>().ok());
+    let denominator = parts.next().and_then(|part| part.parse::<u64>().ok());
+    let (numerator, denominator) = match (numerator, denominator) {
+        (Some(numerator), Some(denominator)) if denominator > 0 => (numerator, denominator),
+        _ => bail!(malformed(format!("invalid time base '{}'", time_base))),
+    };
+
+    let nanos = (units.max(0) as u128)
+        .checked_mul(numerator as u128)
+        .and_then(|scaled| scaled.checked_mul(NANOS_PER_SEC))
+        .map(|scaled| scaled / denominator as u128);
+    match nanos {
+        Some(nanos) if nanos / NANOS_PER_SEC <= u64::max_value() as u128 => Ok(from_nanos(nanos)),
+        _ => bail!(malformed(format!("time {} in {} out of range", units, time_base))),
+    }
+}
+
+/// Parses seconds such as `"84213.460317"` digit by digit, keeping
+/// nanosecond precision however long the book. `"N/A"` means unknown.
+pub fn parse_decimal(text: &str) -> Result<Option<time::Duration>> {
+    let text = text.trim();
+    if text == NOT_AVAILABLE {
+        return Ok(None);
+    }
+
+    let invalid = || malformed(format!("invalid time '{}'", text));
+    let (seconds, fraction) = match text.find('.') {
+        Some(dot) => (&text[..dot], &text[dot + 1..]),
+        None => (text, ""),
+    };
+    // Negative times are clamped to zero like in `rational_time`, but only
+    // once they are known to be numbers.
+    let (negative, seconds) = if seconds.starts_with('-') {
+        (true, &seconds[1..])
+    } else {
+        (false, seconds)
+    };
+    if (seconds.is_empty() && fraction.is_empty())
+        || !seconds.bytes().all(|digit| digit.is_ascii_digit())
+        || !fraction.bytes().all(|digit| digit.is_ascii_digit())
+    {
+        bail!(invalid());
+    }
+
+    if negative {
+        return Ok(Some(time::Duration::from_secs(0)));
+    }
+
+    let seconds: u64 = if seconds.is_empty() {
+        0
+    } else {
+        seconds.parse().map_err(|_| invalid())?
+    };
+    let nanos = fraction
+        .bytes()
+        .chain(b"000000000".iter().cloned())
+        .take(9)
+        .fold(0u32, |nanos, digit| nanos * 10 + (digit - b'0') as u32);
+    Ok(Some(time::Duration::new(seconds, nanos)))
+}
+
+fn from_nanos(nanos: u128) -> time::Duration {
+    time::Duration::new(
+        (nanos / NANOS_PER_SEC) as u64,
+        (nanos % NANOS_PER_SEC) as u32,
+    )
+}
+
+// The fixtures are written by hand after the layout of `ffprobe
+// -print_format json -show_format -show_chapters`, they are not captures.
+#[cfg(test)]
+mod tests {
+    use std::path;
+    use std::time;
+
+    use super::{parse, parse_decimal, rational_time};
+    use metadata::Metadata;
+
+    fn fixture(json: &[u8]) -> Metadata {
+        parse(&path::PathBuf::from("book"), json).unwrap()
+    }
+
+    #[test]
+    fn keeps_precision_of_long_books() {
+        let metadata = fixture(include_bytes!("../../tests/fixtures/ffprobe/long_book.json"));
+        // As an f32 this would be 84213.4609375.
+        assert_eq!(metadata.duration, time::Duration::new(84213, 460_317_000));
+        assert_eq!(metadata.chapters.len(), 3);
+        assert_eq!(metadata.chapters[2].title, "Part Two");
+        assert_eq!(metadata.chapters[2].start, time::Duration::from_millis(42_106_731));
+        assert_eq!(metadata.chapters[2].end, time::Duration::from_millis(84_213_460));
+        assert_eq!(metadata.title, "The Long Book");
+        assert_eq!(metadata.artist, "Jane Author");
+        assert_eq!(metadata.narrator, "John Reader");
+    }
+
+    #[test]
+    fn falls_back_to_chapters_for_unknown_duration() {
+        let metadata = fixture(include_bytes!("../../tests/fixtures/ffprobe/not_available.json"));
+        assert_eq!(metadata.duration, time::Duration::from_millis(1_210_500));
+        assert_eq!(metadata.chapters.len(), 2);
+        assert_eq!(metadata.title, "");
+    }
+
+    #[test]
+    fn reads_files_without_chapters_or_tags() {
+        let metadata = fixture(include_bytes!("../../tests/fixtures/ffprobe/no_chapters.json"));
+        assert_eq!(metadata.duration, time::Duration::new(3600, 26_122_000));
+        assert!(metadata.chapters.is_empty());
+        assert_eq!(metadata.title, "");
+        assert_eq!(metadata.artist, "");
+    }
+
+    #[test]
+    fn converts_matroska_nanoseconds() {
+        let metadata = fixture(include_bytes!("../../tests/fixtures/ffprobe/matroska.json"));
+        let chapters = &metadata.chapters;
+        assert_eq!(metadata.title, "Matroska Book");
+        assert_eq!(chapters[0].title, "Nanoseconds");
+        assert_eq!(chapters[0].end, time::Duration::new(1234, 567_891_234));
+        assert_eq!(chapters[1].title, "");
+        assert_eq!(chapters[1].end, time::Duration::from_secs(3600));
+        assert_eq!(chapters[2].end, time::Duration::from_millis(3_600_500));
+    }
+
+    #[test]
+    fn converts_chapter_track_samples() {
+        let metadata = fixture(include_bytes!("../../tests/fixtures/ffprobe/chapter_track.json"));
+        let chapters = &metadata.chapters;
+        assert_eq!(chapters[1].title, "Two");
+        assert_eq!(chapters[1].start, time::Duration::new(1234, 567_868_480));
+        assert_eq!(chapters[1].end, time::Duration::from_secs(3600));
+    }
+
+    #[test]
+    fn rejects_malformed_output() {
+        assert!(parse(&path::PathBuf::from("book"), b"{\"format\": 1}").is_err());
+        assert!(rational_time(1, "1/0").is_err());
+        assert!(rational_time(1, "fast").is_err());
+        assert!(rational_time(i64::max_value(), "18446744073709551615/1").is_err());
+        assert!(rational_time(i64::max_value(), "4294967296/1").is_err());
+        assert_eq!(
+            rational_time(i64::max_value(), "1/1000000000").unwrap(),
+            time::Duration::new(9_223_372_036, 854_775_807)
+        );
+    }
+
+    #[test]
+    fn parses_decimals_exactly() {
+        let parsed = |text| parse_decimal(text).unwrap();
+        assert_eq!(parsed("N/A"), None);
+        assert_eq!(parsed("12"), Some(time::Duration::from_secs(12)));
+        assert_eq!(parsed(".5"), Some(time::Duration::from_millis(500)));
+        assert_eq!(parsed("1.0000000019"), Some(time::Duration::new(1, 1)));
+        assert_eq!(parsed("-1.5"), Some(time::Duration::from_secs(0)));
+        for text in &["", "-", "-abc", "1.2.3", "1,5", "+1"] {
+            assert!(parse_decimal(text).is_err(), "{:?} parsed", text);
+        }
+    }
+}
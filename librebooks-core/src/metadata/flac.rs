@@ -0,0 +1,149 @@
+use std::fs;
+use std::io::Read;
+use std::path;
+use std::time;
+
+use byteorder::{BigEndian, ByteOrder, ReadBytesExt};
+
+use errors::{ErrorKind, Result};
+use metadata::picture::{cover, picture};
+use metadata::{chapters_from_marks, units_to_time, vorbis, Metadata, MetadataExtractor};
+
+const BLOCK_STREAMINFO: u8 = 0;
+const BLOCK_VORBIS_COMMENT: u8 = 4;
+const BLOCK_CUESHEET: u8 = 5;
+const BLOCK_PICTURE: u8 = 6;
+
+const LAST_BLOCK: u8 = 0x80;
+
+/// Lead-out track numbers of CD and non-CD cue sheets.
+const LEAD_OUT: &[u8] = &[170, 255];
+
+fn malformed<S: Into<String>>(reason: S) -> ErrorKind {
+    ErrorKind::MalformedMedia("flac", reason.into())
+}
+
+pub struct Flac;
+
+impl MetadataExtractor for Flac {
+    fn sniff(&self, header: &[u8]) -> bool {
+        header.starts_with(b"fLaC")
+    }
+
+    fn extract(&self, path: &path::PathBuf) -> Result<Metadata> {
+        read(path)
+    }
+}
+
+/// Reads the metadata blocks: length from STREAMINFO, tags and
+/// `CHAPTERxxx` chapters from the Vorbis comments, the cover from PICTURE
+/// blocks or comments, and chapters from a CUESHEET when the comments have
+/// none.
+fn read(path: &path::PathBuf) -> Result<Metadata> {
+    let mut file = fs::File::open(path)?;
+
+    let mut magic = [0; 4];
+    file.read_exact(&mut magic)?;
+    if !Flac.sniff(&magic) {
+        bail!(malformed("missing fLaC marker"));
+    }
+
+    let mut metadata = Metadata::empty(path);
+    let mut sample_rate = 0;
+    let mut pictures = vec![];
+    let mut comments = vec![];
+    let mut cuesheet = None;
+    loop {
+        let kind = file.read_u8()?;
+        let length = file.read_u24::<BigEndian>()? as usize;
+
+        let mut block = vec![0; length];
+        file.read_exact(&mut block)?;
+
+        match kind & !LAST_BLOCK {
+            BLOCK_STREAMINFO => {
+                let (rate, samples) = stream_info(&block)?;
+                sample_rate = rate;
+                metadata.duration = units_to_time(samples, rate);
+            }
+            BLOCK_VORBIS_COMMENT => comments = vorbis::comments(&block)?,
+            BLOCK_CUESHEET => cuesheet = Some(block),
+            BLOCK_PICTURE => pictures.extend(picture(&block)),
+            _ => {}
+        }
+
+        if kind & LAST_BLOCK != 0 {
+            break;
+        }
+    }
+
+    metadata.artwork = cover(pictures);
+    vorbis::apply(&mut metadata, comments);
+
+    if let Some(cuesheet) = cuesheet {
+        if metadata.chapters.is_empty() && sample_rate > 0 {
+            let marks = cue_tracks(&cuesheet, sample_rate)?;
+            metadata.chapters = chapters_from_marks(marks, metadata.duration);
+        }
+    }
+    Ok(metadata)
+}
+
+/// Sample rate and total number of samples, the latter being 0 when the
+/// encoder did not know it.
+fn stream_info(data: &[u8]) -> Result<(u32, u64)> {
+    if data.len() < 18 {
+        bail!(malformed("truncated STREAMINFO block"));
+    }
+    // 20 bits of sample rate, 3 of channels, 5 of bits per sample and 36 of
+    // total samples.
+    let packed = BigEndian::read_u64(&data[10..18]);
+    Ok(((packed >> 44) as u32, packed & 0xf_ffff_ffff))
+}
+
+/// Start of every track of a CUESHEET block. Binary cue sheets carry no
+/// titles, the tracks are numbered instead.
+fn cue_tracks(data: &[u8], sample_rate: u32) -> Result<Vec<(String, time::Duration)>> {
+    // Media catalog number, lead-in samples, the CD flag and reserved bytes.
+    const HEADER_LEN: usize = 128 + 8 + 1 + 258;
+    if data.len() < HEADER_LEN + 1 {
+        bail!(malformed("truncated CUESHEET block"));
+    }
+    let mut cursor = &data[HEADER_LEN..];
+    let count = cursor.read_u8()?;
+
+    let mut marks = vec![];
+    for _ in 0..count {
+        let offset = cursor.read_u64::<BigEndian>()?;
+        let number = cursor.read_u8()?;
+        // ISRC, track flags and reserved bytes.
+        if cursor.len() < 12 + 14 {
+            bail!(malformed("truncated CUESHEET track"));
+        }
+        cursor = &cursor[12 + 14..];
+
+        let mut start = None;
+        for _ in 0..cursor.read_u8()? {
+            let index_offset = cursor.read_u64::<BigEndian>()?;
+            let index = cursor.read_u8()?;
+            if cursor.len() < 3 {
+                bail!(malformed("truncated CUESHEET index"));
+            }
+            cursor = &cursor[3..];
+            // Index 1 is where the track proper begins, after any pregap.
+            if index == 1 || start.is_none() {
+                start = Some(offset + index_offset);
+            }
+        }
+
+        if LEAD_OUT.contains(&number) {
+            continue;
+        }
+        let start = start.unwrap_or(offset);
+        marks.push((
+            format!("Track {}", number),
+            units_to_time(start, sample_rate),
+        ));
+    }
+    Ok(marks)
+}
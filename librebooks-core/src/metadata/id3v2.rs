@@ -0,0 +1,510 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::Read;
+use std::path;
+use std::time;
+
+use byteorder::{BigEndian, ByteOrder};
+
+use errors::{ErrorKind, Result};
+use metadata::picture::cover;
+use metadata::{Artwork, Chapter, Metadata, MetadataExtractor};
+
+const HEADER_LEN: usize = 10;
+
+const FLAG_UNSYNCHRONISATION: u8 = 0x80;
+const FLAG_EXTENDED_HEADER: u8 = 0x40;
+
+const FRAME_UNSYNCHRONISATION: u16 = 0x0002;
+
+const TOC_TOP_LEVEL: u8 = 0x02;
+const TOC_ORDERED: u8 = 0x01;
+
+struct Frame {
+    id: [u8; 4],
+    data: Vec<u8>,
+}
+
+struct ChapterFrame {
+    id: String,
+    title: Option<String>,
+    start: time::Duration,
+    end: time::Duration,
+    artwork: Option<Artwork>,
+}
+
+struct TocFrame {
+    id: String,
+    title: Option<String>,
+    top_level: bool,
+    ordered: bool,
+    children: Vec<String>,
+}
+
+fn malformed<S: Into<String>>(reason: S) -> ErrorKind {
+    ErrorKind::MalformedMedia("id3v2", reason.into())
+}
+
+pub struct Id3v2;
+
+impl MetadataExtractor for Id3v2 {
+    fn sniff(&self, header: &[u8]) -> bool {
+        sniff(header)
+    }
+
+    fn extract(&self, path: &path::PathBuf) -> Result<Metadata> {
+        read(path)
+    }
+}
+
+fn sniff(header: &[u8]) -> bool {
+    header.len() >= HEADER_LEN && &header[0..3] == b"ID3" && (header[3] == 3 || header[3] == 4)
+}
+
+fn read(path: &path::PathBuf) -> Result<Metadata> {
+    let mut file = fs::File::open(path)?;
+
+    let mut header = [0; HEADER_LEN];
+    file.read_exact(&mut header)?;
+    if !sniff(&header) {
+        bail!(malformed("unsupported tag version"));
+    }
+    let version = header[3];
+    let flags = header[5];
+
+    let mut tag = vec![0; syncsafe(&header[6..10]) as usize];
+    file.read_exact(&mut tag)?;
+
+    // v2.3 unsynchronises the whole tag, v2.4 does it frame by frame.
+    if version == 3 && flags & FLAG_UNSYNCHRONISATION != 0 {
+        tag = resync(&tag);
+    }
+
+    let mut body = &tag[..];
+    if flags & FLAG_EXTENDED_HEADER != 0 {
+        if body.len() < 4 {
+            bail!(malformed("truncated extended header"));
+        }
+        let skip = if version == 3 {
+            BigEndian::read_u32(&body[0..4]) as usize + 4
+        } else {
+            syncsafe(&body[0..4]) as usize
+        };
+        if skip > body.len() {
+            bail!(malformed("extended header overruns the tag"));
+        }
+        body = &body[skip..];
+    }
+
+    let unsynchronised = version == 4 && flags & FLAG_UNSYNCHRONISATION != 0;
+
+    let mut metadata = Metadata::empty(path);
+
+    let mut chapters = vec![];
+    let mut tocs = vec![];
+    let mut pictures = vec![];
+    let mut length = None;
+
+    for frame in frames(body, version, unsynchronised)? {
+        match &frame.id {
+            b"TIT2" => metadata.title = text(&frame.data).unwrap_or_default(),
+            b"TPE1" => metadata.artist = text(&frame.data).unwrap_or_default(),
+            b"TALB" => metadata.album = text(&frame.data).unwrap_or_default(),
+            b"TCOM" => metadata.narrator = text(&frame.data).unwrap_or_default(),
+            b"TLEN" => {
+                length = text(&frame.data)
+                    .and_then(|millis| millis.trim().parse::<u64>().ok())
+                    .map(time::Duration::from_millis)
+            }
+            b"CHAP" => chapters.push(chapter(&frame.data, version)?),
+            b"CTOC" => tocs.push(toc(&frame.data, version)?),
+            b"APIC" => pictures.extend(picture(&frame.data)),
+            _ => {}
+        }
+    }
+
+    metadata.artwork = cover(pictures);
+
+    metadata.chapters = ordered_chapters(chapters, &tocs);
+    metadata.duration = length.unwrap_or(time::Duration::from_secs(0));
+
+    Ok(metadata)
+}
+
+/// Frames in `body`, undoing the unsynchronisation of v2.4 frames, which is
+/// flagged on each frame or for all of them in the tag header.
+fn frames(mut body: &[u8], version: u8, unsynchronised: bool) -> Result<Vec<Frame>> {
+    let mut frames = vec![];
+
+    while body.len() >= HEADER_LEN && body[0] != 0 {
+        let mut id = [0; 4];
+        id.copy_from_slice(&body[0..4]);
+        let size = if version == 4 {
+            syncsafe(&body[4..8])
+        } else {
+            BigEndian::read_u32(&body[4..8])
+        } as usize;
+        let flags = BigEndian::read_u16(&body[8..10]);
+
+        if HEADER_LEN + size > body.len() {
+            bail!(malformed(format!(
+                "frame {} overruns the tag",
+                String::from_utf8_lossy(&id)
+            )));
+        }
+        let mut data = &body[HEADER_LEN..HEADER_LEN + size];
+        body = &body[HEADER_LEN + size..];
+
+        let (compressed, encrypted, length_indicator) = if version == 4 {
+            (flags & 0x0008 != 0, flags & 0x0004 != 0, flags & 0x0001 != 0)
+        } else {
+            (flags & 0x0080 != 0, flags & 0x0040 != 0, false)
+        };
+        if compressed || encrypted {
+            continue;
+        }
+        if length_indicator && data.len() >= 4 {
+            data = &data[4..];
+        }
+
+        let data = if version == 4 && (unsynchronised || flags & FRAME_UNSYNCHRONISATION != 0) {
+            resync(data)
+        } else {
+            data.to_vec()
+        };
+        frames.push(Frame { id, data });
+    }
+
+    Ok(frames)
+}
+
+fn chapter(data: &[u8], version: u8) -> Result<ChapterFrame> {
+    let (id, rest) = split_terminated(data);
+    if rest.len() < 16 {
+        bail!(malformed("truncated CHAP frame"));
+    }
+
+    let mut chapter = ChapterFrame {
+        id: latin1(id),
+        title: None,
+        start: time::Duration::from_millis(BigEndian::read_u32(&rest[0..4]) as u64),
+        end: time::Duration::from_millis(BigEndian::read_u32(&rest[4..8]) as u64),
+        artwork: None,
+    };
+
+    for frame in frames(&rest[16..], version, false)? {
+        match &frame.id {
+            b"TIT2" => chapter.title = text(&frame.data),
+            b"TIT3" if chapter.title.is_none() => chapter.title = text(&frame.data),
+            b"APIC" => chapter.artwork = picture(&frame.data).map(|(_, artwork)| artwork),
+            _ => {}
+        }
+    }
+
+    Ok(chapter)
+}
+
+fn toc(data: &[u8], version: u8) -> Result<TocFrame> {
+    let (id, rest) = split_terminated(data);
+    if rest.len() < 2 {
+        bail!(malformed("truncated CTOC frame"));
+    }
+
+    let flags = rest[0];
+    let count = rest[1];
+    let mut rest = &rest[2..];
+
+    let mut children = vec![];
+    for _ in 0..count {
+        let (child, tail) = split_terminated(rest);
+        children.push(latin1(child));
+        rest = tail;
+    }
+
+    let mut title = None;
+    for frame in frames(rest, version, false)? {
+        match &frame.id {
+            b"TIT2" => title = text(&frame.data),
+            b"TIT3" if title.is_none() => title = text(&frame.data),
+            _ => {}
+        }
+    }
+
+    Ok(TocFrame {
+        id: latin1(id),
+        title,
+        top_level: flags & TOC_TOP_LEVEL != 0,
+        ordered: flags & TOC_ORDERED != 0,
+        children,
+    })
+}
+
+/// Walks the table of contents from its top-level entry, falling back to
+/// chronological order for unordered tables or tags without one. Nested
+/// tables become chapters that hold the entries listed in them.
+fn ordered_chapters(chapters: Vec<ChapterFrame>, tocs: &[TocFrame]) -> Vec<Chapter> {
+    let mut by_id: HashMap<String, ChapterFrame> = chapters
+        .into_iter()
+        .map(|chapter| (chapter.id.clone(), chapter))
+        .collect();
+
+    let mut ordered = vec![];
+    if let Some(root) = tocs.iter().find(|toc| toc.top_level).or(tocs.first()) {
+        let mut visited = vec![root.id.clone()];
+        walk_toc(root, tocs, &mut by_id, &mut ordered, &mut visited, None);
+    }
+
+    // Chapters left out of the table go at the top level, before the first
+    // entry starting after them.
+    let mut rest: Vec<ChapterFrame> = by_id.into_iter().map(|(_, chapter)| chapter).collect();
+    rest.sort_by_key(|chapter| chapter.start);
+    for chapter in rest {
+        let at = ordered
+            .iter()
+            .position(|other| other.parent.is_none() && other.start > chapter.start)
+            .unwrap_or(ordered.len());
+        for other in ordered[at..].iter_mut() {
+            other.parent = other.parent.map(|parent| parent + 1);
+        }
+        ordered.insert(at, entry(chapter, None, 0));
+    }
+
+    // Sorting would break the indices of parents, so only flat tables are.
+    let nested = ordered.iter().any(|chapter| chapter.parent.is_some());
+    if !nested && !tocs.iter().any(|toc| toc.ordered) {
+        ordered.sort_by_key(|chapter| chapter.start);
+    }
+
+    ordered
+}
+
+fn walk_toc(
+    toc: &TocFrame,
+    tocs: &[TocFrame],
+    chapters: &mut HashMap<String, ChapterFrame>,
+    ordered: &mut Vec<Chapter>,
+    visited: &mut Vec<String>,
+    parent: Option<usize>,
+) {
+    let depth = parent.map_or(0, |parent| ordered[parent].depth + 1);
+
+    for child in toc.children.iter() {
+        if let Some(chapter) = chapters.remove(child) {
+            ordered.push(entry(chapter, parent, depth));
+            continue;
+        }
+        let nested = match tocs.iter().find(|toc| &toc.id == child) {
+            Some(nested) if !visited.contains(&nested.id) => nested,
+            _ => continue,
+        };
+        visited.push(nested.id.clone());
+
+        let index = ordered.len();
+        ordered.push(Chapter {
+            title: nested.title.clone().unwrap_or_else(|| nested.id.clone()),
+            start: time::Duration::from_secs(0),
+            end: time::Duration::from_secs(0),
+            artwork: None,
+            parent,
+            depth,
+        });
+        walk_toc(nested, tocs, chapters, ordered, visited, Some(index));
+
+        // A table spans the entries below it, and is dropped without any.
+        let start = ordered[index + 1..].iter().map(|chapter| chapter.start).min();
+        let end = ordered[index + 1..].iter().map(|chapter| chapter.end).max();
+        match (start, end) {
+            (Some(start), Some(end)) => {
+                ordered[index].start = start;
+                ordered[index].end = end;
+            }
+            _ => {
+                ordered.pop();
+            }
+        }
+    }
+}
+
+fn entry(chapter: ChapterFrame, parent: Option<usize>, depth: usize) -> Chapter {
+    Chapter {
+        title: chapter.title.unwrap_or(chapter.id),
+        start: chapter.start,
+        end: chapter.end,
+        artwork: chapter.artwork,
+        parent,
+        depth,
+    }
+}
+
+/// Picture type and image of an APIC frame.
+fn picture(data: &[u8]) -> Option<(u32, Artwork)> {
+    if data.is_empty() {
+        return None;
+    }
+    let encoding = data[0];
+    let (mime, rest) = split_terminated(&data[1..]);
+    if rest.is_empty() {
+        return None;
+    }
+    let (_, image) = split_encoded(&rest[1..], encoding);
+
+    Some((
+        rest[0] as u32,
+        Artwork {
+            mime: latin1(mime),
+            data: image.to_vec(),
+        },
+    ))
+}
+
+fn text(data: &[u8]) -> Option<String> {
+    if data.is_empty() {
+        return None;
+    }
+    let (value, _) = split_encoded(&data[1..], data[0]);
+    let value = decode(value, data[0]);
+    if value.is_empty() {
+        None
+    } else {
+        Some(value)
+    }
+}
+
+fn decode(data: &[u8], encoding: u8) -> String {
+    match encoding {
+        1 | 2 => {
+            let (data, big_endian) = if data.starts_with(&[0xfe, 0xff]) {
+                (&data[2..], true)
+            } else if data.starts_with(&[0xff, 0xfe]) {
+                (&data[2..], false)
+            } else {
+                (data, encoding == 2)
+            };
+            let units: Vec<u16> = data
+                .chunks(2)
+                .filter(|pair| pair.len() == 2)
+                .map(|pair| {
+                    if big_endian {
+                        BigEndian::read_u16(pair)
+                    } else {
+                        (pair[1] as u16) << 8 | pair[0] as u16
+                    }
+                })
+                .collect();
+            String::from_utf16_lossy(&units)
+        }
+        3 => String::from_utf8_lossy(data).into_owned(),
+        _ => latin1(data),
+    }
+}
+
+fn latin1(data: &[u8]) -> String {
+    data.iter().map(|&byte| byte as char).collect()
+}
+
+fn split_terminated(data: &[u8]) -> (&[u8], &[u8]) {
+    match data.iter().position(|&byte| byte == 0) {
+        Some(end) => (&data[..end], &data[end + 1..]),
+        None => (data, &[]),
+    }
+}
+
+/// Splits at the terminator of the given text encoding, which is two zero
+/// bytes on an even offset for UTF-16 and a single zero byte otherwise.
+fn split_encoded(data: &[u8], encoding: u8) -> (&[u8], &[u8]) {
+    if encoding == 1 || encoding == 2 {
+        let mut end = 0;
+        while end + 1 < data.len() {
+            if data[end] == 0 && data[end + 1] == 0 {
+                return (&data[..end], &data[end + 2..]);
+            }
+            end += 2;
+        }
+        (data, &[])
+    } else {
+        split_terminated(data)
+    }
+}
+
+fn syncsafe(bytes: &[u8]) -> u32 {
+    bytes
+        .iter()
+        .fold(0, |value, &byte| value << 7 | (byte & 0x7f) as u32)
+}
+
+fn resync(data: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(data.len());
+    let mut previous = 0;
+    for &byte in data {
+        if !(previous == 0xff && byte == 0) {
+            output.push(byte);
+        }
+        previous = byte;
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{frames, ordered_chapters, ChapterFrame, TocFrame};
+
+    fn frame(id: &[u8], flags: u8, data: &[u8]) -> Vec<u8> {
+        let mut frame = id.to_vec();
+        frame.extend_from_slice(&[0, 0, 0, data.len() as u8, 0, flags]);
+        frame.extend_from_slice(data);
+        frame
+    }
+
+    #[test]
+    fn resyncs_flagged_v24_frames() {
+        let mut body = frame(b"APIC", 0x02, &[0xff, 0x00, 0xe0]);
+        body.extend(frame(b"TIT2", 0, &[0xff, 0x00, 0xe0]));
+
+        let parsed = frames(&body, 4, false).unwrap();
+        assert_eq!(parsed[0].data, vec![0xff, 0xe0]);
+        assert_eq!(parsed[1].data, vec![0xff, 0x00, 0xe0]);
+
+        let parsed = frames(&body, 4, true).unwrap();
+        assert_eq!(parsed[1].data, vec![0xff, 0xe0]);
+    }
+
+    #[test]
+    fn places_chapters_missing_from_the_table_by_time() {
+        let chapter = |id: &str, start: u64| ChapterFrame {
+            id: id.to_string(),
+            title: None,
+            start: Duration::from_secs(start),
+            end: Duration::from_secs(start + 10),
+            artwork: None,
+        };
+        let toc = |id: &str, top_level: bool, children: &[&str]| TocFrame {
+            id: id.to_string(),
+            title: None,
+            top_level,
+            ordered: true,
+            children: children.iter().map(|child| child.to_string()).collect(),
+        };
+        let chapters = vec![
+            chapter("intro", 0),
+            chapter("one", 10),
+            chapter("two", 20),
+            chapter("outro", 30),
+        ];
+        let tocs = vec![toc("root", true, &["part"]), toc("part", false, &["one", "two"])];
+
+        let ordered = ordered_chapters(chapters, &tocs);
+        let titles: Vec<&str> = ordered.iter().map(|chapter| chapter.title.as_str()).collect();
+        assert_eq!(titles, vec!["intro", "part", "one", "two", "outro"]);
+        assert_eq!(ordered[2].parent, Some(1));
+        assert_eq!(ordered[3].parent, Some(1));
+        assert_eq!(ordered[4].parent, None);
+    }
+
+    #[test]
+    fn leaves_v23_frames_to_the_tag() {
+        let body = frame(b"APIC", 0x02, &[0xff, 0x00, 0xe0]);
+        assert_eq!(frames(&body, 3, false).unwrap()[0].data, vec![0xff, 0x00, 0xe0]);
+    }
+}
@@ -0,0 +1,538 @@
+use std::env;
+use std::fs;
+use std::io::{Read, Seek, SeekFrom};
+use std::path;
+use std::time;
+
+use byteorder::{BigEndian, ByteOrder};
+
+use errors::{ErrorKind, Result};
+use metadata::{Chapter, Metadata, MetadataExtractor};
+
+const EBML: u32 = 0x1A45_DFA3;
+const SEGMENT: u32 = 0x1853_8067;
+const SEEK_HEAD: u32 = 0x114D_9B74;
+const SEEK: u32 = 0x4DBB;
+const SEEK_ID: u32 = 0x53AB;
+const SEEK_POSITION: u32 = 0x53AC;
+const INFO: u32 = 0x1549_A966;
+const TIMECODE_SCALE: u32 = 0x2A_D7B1;
+const DURATION: u32 = 0x4489;
+const INFO_TITLE: u32 = 0x7BA9;
+const CLUSTER: u32 = 0x1F43_B675;
+
+const CHAPTERS: u32 = 0x1043_A770;
+const EDITION_ENTRY: u32 = 0x45B9;
+const EDITION_FLAG_HIDDEN: u32 = 0x45BD;
+const EDITION_FLAG_DEFAULT: u32 = 0x45DB;
+const CHAPTER_ATOM: u32 = 0xB6;
+const CHAPTER_TIME_START: u32 = 0x91;
+const CHAPTER_TIME_END: u32 = 0x92;
+const CHAPTER_FLAG_HIDDEN: u32 = 0x98;
+const CHAPTER_FLAG_ENABLED: u32 = 0x4598;
+const CHAPTER_SEGMENT_UID: u32 = 0x6E67;
+const CHAPTER_DISPLAY: u32 = 0x80;
+const CHAP_STRING: u32 = 0x85;
+const CHAP_LANGUAGE: u32 = 0x437C;
+const CHAP_LANGUAGE_IETF: u32 = 0x437D;
+
+const TAGS: u32 = 0x1254_C367;
+const TAG: u32 = 0x7373;
+const TARGETS: u32 = 0x63C0;
+const TAG_TRACK_UID: u32 = 0x63C5;
+const TAG_CHAPTER_UID: u32 = 0x63C4;
+const SIMPLE_TAG: u32 = 0x67C8;
+const TAG_NAME: u32 = 0x45A3;
+const TAG_STRING: u32 = 0x4487;
+
+const DEFAULT_TIMECODE_SCALE: u64 = 1_000_000;
+
+/// Level 1 elements worth reading are small, anything bigger is corrupt.
+const MAX_SECTION_LEN: u64 = 16 * 1024 * 1024;
+
+/// ISO 639-2 codes of chapter languages against the ISO 639-1 codes of
+/// locales, for the languages books are most often in.
+const LANGUAGES: &[(&str, &str)] = &[
+    ("eng", "en"),
+    ("ger", "de"),
+    ("deu", "de"),
+    ("fre", "fr"),
+    ("fra", "fr"),
+    ("spa", "es"),
+    ("ita", "it"),
+    ("dut", "nl"),
+    ("nld", "nl"),
+    ("por", "pt"),
+    ("rus", "ru"),
+    ("pol", "pl"),
+    ("cze", "cs"),
+    ("ces", "cs"),
+    ("swe", "sv"),
+    ("nor", "no"),
+    ("dan", "da"),
+    ("fin", "fi"),
+    ("jpn", "ja"),
+    ("chi", "zh"),
+    ("zho", "zh"),
+];
+
+fn malformed<S: Into<String>>(reason: S) -> ErrorKind {
+    ErrorKind::MalformedMedia("matroska", reason.into())
+}
+
+/// Matroska and WebM, including nested chapters of the default edition.
+pub struct Matroska;
+
+impl MetadataExtractor for Matroska {
+    fn sniff(&self, header: &[u8]) -> bool {
+        header.len() >= 4 && BigEndian::read_u32(&header[0..4]) == EBML
+    }
+
+    fn extract(&self, path: &path::PathBuf) -> Result<Metadata> {
+        read(path)
+    }
+}
+
+struct Element<'a> {
+    id: u32,
+    data: &'a [u8],
+}
+
+/// A chapter atom before it is placed in the flat chapter list.
+struct Atom {
+    title: String,
+    start: time::Duration,
+    end: Option<time::Duration>,
+    children: Vec<Atom>,
+}
+
+fn read(path: &path::PathBuf) -> Result<Metadata> {
+    let mut file = fs::File::open(path)?;
+    let length = file.metadata()?.len();
+
+    let (id, size) = element_header(&mut file)?;
+    if id != EBML {
+        bail!(malformed("missing EBML header"));
+    }
+    file.seek(SeekFrom::Current(known(size)? as i64))?;
+
+    let (id, size) = element_header(&mut file)?;
+    if id != SEGMENT {
+        bail!(malformed("missing segment"));
+    }
+    let start = file.seek(SeekFrom::Current(0))?;
+    let end = size.map_or(length, |size| (start + size).min(length));
+
+    // Sections usually come before the first cluster, the seek head says
+    // where to find those that do not.
+    let mut info = None;
+    let mut chapters = None;
+    let mut tags = None;
+    let mut seeks = vec![];
+    let mut offset = start;
+    while offset < end {
+        file.seek(SeekFrom::Start(offset))?;
+        let (id, size) = element_header(&mut file)?;
+        let size = match size {
+            Some(size) => size,
+            None => break,
+        };
+        let data = file.seek(SeekFrom::Current(0))?;
+        match id {
+            SEEK_HEAD => seeks = seek_head(&section(&mut file, size)?)?,
+            INFO => info = Some(section(&mut file, size)?),
+            CHAPTERS => chapters = Some(section(&mut file, size)?),
+            TAGS => tags = Some(section(&mut file, size)?),
+            CLUSTER => break,
+            _ => {}
+        }
+        offset = data + size;
+    }
+
+    for (id, position) in seeks {
+        let slot = match id {
+            INFO => &mut info,
+            CHAPTERS => &mut chapters,
+            TAGS => &mut tags,
+            _ => continue,
+        };
+        if slot.is_some() {
+            continue;
+        }
+        // Positions come from the file, and may point anywhere.
+        let target = match start.checked_add(position) {
+            Some(target) if target < length => target,
+            _ => continue,
+        };
+        file.seek(SeekFrom::Start(target))?;
+        let (found, size) = element_header(&mut file)?;
+        if found == id {
+            *slot = Some(section(&mut file, known(size)?)?);
+        }
+    }
+
+    let mut metadata = Metadata::empty(path);
+    if let Some(tags) = tags {
+        read_tags(&mut metadata, &tags)?;
+    }
+    if let Some(info) = info {
+        read_info(&mut metadata, &info)?;
+    }
+    if let Some(chapters) = chapters {
+        let atoms = default_edition(&chapters)?;
+        flatten(atoms, None, 0, metadata.duration, &mut metadata.chapters);
+    }
+    Ok(metadata)
+}
+
+fn section(file: &mut fs::File, size: u64) -> Result<Vec<u8>> {
+    if size > MAX_SECTION_LEN {
+        bail!(malformed("oversized section"));
+    }
+    let mut data = vec![0; size as usize];
+    file.read_exact(&mut data)?;
+    Ok(data)
+}
+
+fn known(size: Option<u64>) -> Result<u64> {
+    match size {
+        Some(size) => Ok(size),
+        None => bail!(malformed("element of unknown size")),
+    }
+}
+
+/// Positions of level 1 elements, relative to the segment data.
+fn seek_head(data: &[u8]) -> Result<Vec<(u32, u64)>> {
+    let mut seeks = vec![];
+    for seek in children(data, SEEK)? {
+        let mut id = None;
+        let mut position = None;
+        for element in elements(seek)? {
+            match element.id {
+                SEEK_ID => id = Some(unsigned(element.data) as u32),
+                SEEK_POSITION => position = Some(unsigned(element.data)),
+                _ => {}
+            }
+        }
+        if let (Some(id), Some(position)) = (id, position) {
+            seeks.push((id, position));
+        }
+    }
+    Ok(seeks)
+}
+
+fn read_info(metadata: &mut Metadata, data: &[u8]) -> Result<()> {
+    let mut scale = DEFAULT_TIMECODE_SCALE;
+    let mut duration = None;
+    for element in elements(data)? {
+        match element.id {
+            TIMECODE_SCALE => scale = unsigned(element.data),
+            DURATION => duration = float(element.data),
+            INFO_TITLE if metadata.title.is_empty() => metadata.title = text(element.data),
+            _ => {}
+        }
+    }
+    if let Some(duration) = duration {
+        let nanos = (duration * scale as f64).max(0.0) as u64;
+        metadata.duration = from_nanos(nanos);
+    }
+    Ok(())
+}
+
+/// Tags of the whole file, tags aimed at a single track or chapter are
+/// left alone.
+fn read_tags(metadata: &mut Metadata, data: &[u8]) -> Result<()> {
+    for tag in children(data, TAG)? {
+        let tag = elements(tag)?;
+        let targeted = tag
+            .iter()
+            .filter(|element| element.id == TARGETS)
+            .map(|targets| elements(targets.data))
+            .collect::<Result<Vec<_>>>()?
+            .iter()
+            .flat_map(|targets| targets.iter())
+            .any(|target| {
+                (target.id == TAG_TRACK_UID || target.id == TAG_CHAPTER_UID)
+                    && unsigned(target.data) != 0
+            });
+        if targeted {
+            continue;
+        }
+
+        for simple in tag.iter().filter(|element| element.id == SIMPLE_TAG) {
+            let mut name = None;
+            let mut value = None;
+            for element in elements(simple.data)? {
+                match element.id {
+                    TAG_NAME => name = Some(text(element.data).to_uppercase()),
+                    TAG_STRING => value = Some(text(element.data)),
+                    _ => {}
+                }
+            }
+            let (name, value) = match (name, value) {
+                (Some(name), Some(value)) => (name, value),
+                _ => continue,
+            };
+            let field = match name.as_str() {
+                "TITLE" => &mut metadata.title,
+                "ARTIST" => &mut metadata.artist,
+                "ALBUM" => &mut metadata.album,
+                "COMPOSER" => &mut metadata.narrator,
+                _ => continue,
+            };
+            if field.is_empty() {
+                *field = value;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Chapters of the default edition, or of the first visible one.
+fn default_edition(data: &[u8]) -> Result<Vec<Atom>> {
+    let editions = children(data, EDITION_ENTRY)?;
+
+    let mut chosen = None;
+    for edition in editions {
+        let flags = elements(edition)?;
+        let flag = |id| {
+            flags
+                .iter()
+                .find(|element| element.id == id)
+                .map_or(false, |element| unsigned(element.data) != 0)
+        };
+        if flag(EDITION_FLAG_HIDDEN) {
+            continue;
+        }
+        if flag(EDITION_FLAG_DEFAULT) {
+            chosen = Some(edition);
+            break;
+        }
+        chosen = chosen.or(Some(edition));
+    }
+
+    let languages = preferred_languages();
+    let mut atoms = vec![];
+    if let Some(edition) = chosen {
+        for data in children(edition, CHAPTER_ATOM)? {
+            atoms.extend(atom(data, &languages)?);
+        }
+    }
+    Ok(atoms)
+}
+
+/// Reads a chapter atom and those nested in it. Hidden and disabled
+/// chapters, and those of other linked files, are dropped.
+fn atom(data: &[u8], languages: &[String]) -> Result<Option<Atom>> {
+    let mut start = None;
+    let mut end = None;
+    let mut displays = vec![];
+    let mut children = vec![];
+    for element in elements(data)? {
+        match element.id {
+            CHAPTER_TIME_START => start = Some(from_nanos(unsigned(element.data))),
+            CHAPTER_TIME_END => end = Some(from_nanos(unsigned(element.data))),
+            CHAPTER_FLAG_HIDDEN if unsigned(element.data) != 0 => return Ok(None),
+            CHAPTER_FLAG_ENABLED if unsigned(element.data) == 0 => return Ok(None),
+            CHAPTER_SEGMENT_UID if !element.data.is_empty() => return Ok(None),
+            CHAPTER_DISPLAY => displays.push(display(element.data)?),
+            CHAPTER_ATOM => children.extend(atom(element.data, languages)?),
+            _ => {}
+        }
+    }
+
+    let start = match start {
+        Some(start) => start,
+        None => bail!(malformed("chapter without a start")),
+    };
+    Ok(Some(Atom {
+        title: pick_title(displays, languages),
+        start,
+        end,
+        children,
+    }))
+}
+
+/// Title of a chapter display along with its languages, reduced to ISO
+/// 639-1 codes where possible.
+fn display(data: &[u8]) -> Result<(String, Vec<String>)> {
+    let mut title = String::new();
+    let mut languages = vec![];
+    for element in elements(data)? {
+        match element.id {
+            CHAP_STRING => title = text(element.data),
+            CHAP_LANGUAGE | CHAP_LANGUAGE_IETF => {
+                languages.push(primary_language(&text(element.data)))
+            }
+            _ => {}
+        }
+    }
+    if languages.is_empty() {
+        languages.push("en".to_string());
+    }
+    Ok((title, languages))
+}
+
+/// Takes the title in the language the listener prefers, or the first one.
+fn pick_title(displays: Vec<(String, Vec<String>)>, languages: &[String]) -> String {
+    let rank = |display: &(String, Vec<String>)| {
+        languages
+            .iter()
+            .position(|language| display.1.contains(language))
+            .unwrap_or(languages.len())
+    };
+    let best = displays
+        .iter()
+        .enumerate()
+        .min_by_key(|&(index, display)| (rank(display), index))
+        .map(|(index, _)| index);
+    match best {
+        Some(index) => displays.into_iter().nth(index).map(|(title, _)| title).unwrap_or_default(),
+        None => String::new(),
+    }
+}
+
+/// Languages of the user's locale, most wanted first, English as the last
+/// resort.
+fn preferred_languages() -> Vec<String> {
+    let mut languages: Vec<String> = vec![];
+    let wanted = ["LANGUAGE", "LC_ALL", "LC_MESSAGES", "LANG"]
+        .iter()
+        .filter_map(|variable| env::var(variable).ok())
+        .flat_map(|value| {
+            value
+                .split(':')
+                .map(primary_language)
+                .collect::<Vec<String>>()
+        })
+        .chain(Some("en".to_string()));
+    for language in wanted {
+        if language.is_empty() || language == "c" || language == "posix" {
+            continue;
+        }
+        if !languages.contains(&language) {
+            languages.push(language);
+        }
+    }
+    languages
+}
+
+/// `en_GB.UTF-8`, `en-GB` and `eng` all become `en`.
+fn primary_language(tag: &str) -> String {
+    let primary = tag
+        .split(|c| c == '_' || c == '-' || c == '.' || c == '@')
+        .next()
+        .unwrap_or("")
+        .to_lowercase();
+    LANGUAGES
+        .iter()
+        .find(|&&(long, _)| long == primary)
+        .map(|&(_, short)| short.to_string())
+        .unwrap_or(primary)
+}
+
+/// Lays the chapter tree out as a list where parents come before their
+/// children. Chapters without an end run until the next sibling, the last
+/// one until its parent ends.
+fn flatten(
+    mut atoms: Vec<Atom>,
+    parent: Option<usize>,
+    depth: usize,
+    until: time::Duration,
+    chapters: &mut Vec<Chapter>,
+) {
+    atoms.sort_by_key(|atom| atom.start);
+    let starts: Vec<time::Duration> = atoms.iter().map(|atom| atom.start).collect();
+
+    for (index, atom) in atoms.into_iter().enumerate() {
+        let end = atom
+            .end
+            .or_else(|| starts.get(index + 1).cloned())
+            .unwrap_or(until)
+            .max(atom.start);
+        chapters.push(Chapter {
+            title: atom.title,
+            start: atom.start,
+            end,
+            artwork: None,
+            parent,
+            depth,
+        });
+        let position = chapters.len() - 1;
+        flatten(atom.children, Some(position), depth + 1, end, chapters);
+    }
+}
+
+fn children(data: &[u8], id: u32) -> Result<Vec<&[u8]>> {
+    Ok(elements(data)?
+        .into_iter()
+        .filter(|element| element.id == id)
+        .map(|element| element.data)
+        .collect())
+}
+
+fn elements(mut data: &[u8]) -> Result<Vec<Element>> {
+    let mut elements = vec![];
+    while !data.is_empty() {
+        let (id, size) = element_header(&mut data)?;
+        let size = known(size)? as usize;
+        if size > data.len() {
+            bail!(malformed("element overruns its parent"));
+        }
+        elements.push(Element {
+            id,
+            data: &data[..size],
+        });
+        data = &data[size..];
+    }
+    Ok(elements)
+}
+
+/// Reads an element ID, which keeps its length marker, and a data size,
+/// which does not. A size of all ones means the size is unknown.
+fn element_header<R: Read>(reader: &mut R) -> Result<(u32, Option<u64>)> {
+    let (id, _) = vint(reader, 4)?;
+    let (size, length) = vint(reader, 8)?;
+    let mask = (1u64 << (7 * length)) - 1;
+    let size = size & mask;
+    Ok((id as u32, if size == mask { None } else { Some(size) }))
+}
+
+/// A variable length integer with its marker bit, and its length in bytes.
+fn vint<R: Read>(reader: &mut R, max: usize) -> Result<(u64, usize)> {
+    let mut first = [0; 1];
+    reader.read_exact(&mut first)?;
+    let length = first[0].leading_zeros() as usize + 1;
+    if length > max {
+        bail!(malformed("invalid variable length integer"));
+    }
+
+    let mut value = first[0] as u64;
+    let mut rest = [0; 7];
+    reader.read_exact(&mut rest[..length - 1])?;
+    for &byte in &rest[..length - 1] {
+        value = value << 8 | byte as u64;
+    }
+    Ok((value, length))
+}
+
+fn unsigned(data: &[u8]) -> u64 {
+    data.iter().take(8).fold(0, |value, &byte| value << 8 | byte as u64)
+}
+
+fn float(data: &[u8]) -> Option<f64> {
+    match data.len() {
+        4 => Some(BigEndian::read_f32(data) as f64),
+        8 => Some(BigEndian::read_f64(data)),
+        _ => None,
+    }
+}
+
+fn text(data: &[u8]) -> String {
+    let end = data.iter().position(|&byte| byte == 0).unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..end]).trim().to_string()
+}
+
+fn from_nanos(nanos: u64) -> time::Duration {
+    time::Duration::new(nanos / 1_000_000_000, (nanos % 1_000_000_000) as u32)
+}
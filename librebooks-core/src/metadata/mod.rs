@@ -0,0 +1,269 @@
+use std::fmt;
+use std::fs;
+use std::io::Read;
+use std::path;
+use std::time;
+
+use errors::{ErrorKind, Result};
+
+mod base64;
+mod cue;
+mod ffprobe;
+mod flac;
+mod id3v2;
+mod matroska;
+mod mp4;
+mod ogg;
+mod picture;
+mod tree;
+mod vorbis;
+
+pub use self::tree::nest_by_title;
+
+/// Reads the metadata of one container format.
+pub trait MetadataExtractor {
+    /// Tells from the first bytes of a file whether it is in this format.
+    fn sniff(&self, header: &[u8]) -> bool;
+
+    /// Reads what the format offers. Anything unknown is left empty, to be
+    /// filled in by the extractors that follow.
+    fn extract(&self, path: &path::PathBuf) -> Result<Metadata>;
+}
+
+/// Extractors in order of priority, ffprobe last as it reads anything.
+const EXTRACTORS: &[&MetadataExtractor] = &[
+    &mp4::Mp4,
+    &id3v2::Id3v2,
+    &flac::Flac,
+    &ogg::Ogg,
+    &matroska::Matroska,
+    &ffprobe::Ffprobe,
+];
+
+/// Image files next to the audio that are taken for the cover, best first.
+const FOLDER_IMAGES: &[&str] = &["cover", "folder", "front", "album"];
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Artwork {
+    pub mime: String,
+    pub data: Vec<u8>,
+}
+
+impl fmt::Debug for Artwork {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Artwork({}, {} bytes)", self.mime, self.data.len())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Chapter {
+    pub title: String,
+    pub start: time::Duration,
+    pub end: time::Duration,
+    pub artwork: Option<Artwork>,
+    /// Index of the enclosing chapter, which comes earlier in the list.
+    #[serde(default)]
+    pub parent: Option<usize>,
+    /// How deeply the chapter is nested, 0 for top level chapters.
+    #[serde(default)]
+    pub depth: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Metadata {
+    #[serde(with = "::store::os_path")]
+    pub path: path::PathBuf,
+    pub title: String,
+    pub artist: String,
+    pub album: String,
+    /// Whoever reads the book, taken from the composer tag.
+    #[serde(default)]
+    pub narrator: String,
+    pub duration: time::Duration,
+    pub chapters: Vec<Chapter>,
+    #[serde(default)]
+    pub artwork: Option<Artwork>,
+}
+
+impl Metadata {
+    /// Reads the tags and chapters of a file with every extractor that
+    /// recognises its format, best first. A CUE sheet next to the file
+    /// replaces the chapters found inside, or names them when they match its
+    /// tracks, and without an embedded cover an image from the same folder
+    /// is used.
+    pub fn from_file(path: &path::PathBuf) -> Result<Metadata> {
+        let header = read_header(path)?;
+
+        let mut merged: Option<Metadata> = None;
+        let mut failure = None;
+        for extractor in EXTRACTORS {
+            if !extractor.sniff(&header) {
+                continue;
+            }
+            if merged.as_ref().map_or(false, Metadata::is_complete) {
+                break;
+            }
+            match extractor.extract(path) {
+                Ok(metadata) => match merged {
+                    Some(ref mut merged) => merged.fill(metadata),
+                    None => merged = Some(metadata),
+                },
+                Err(err) => {
+                    failure.get_or_insert(err);
+                }
+            }
+        }
+
+        let mut metadata = match (merged, failure) {
+            (Some(metadata), _) => metadata,
+            (None, Some(err)) => return Err(err),
+            (None, None) => bail!(ErrorKind::MalformedMedia("audio", "unknown format".into())),
+        };
+
+        if metadata.duration == time::Duration::from_secs(0) {
+            if let Some(end) = metadata.chapters.iter().map(|chapter| chapter.end).max() {
+                metadata.duration = end;
+            }
+        }
+        if let Some((sheet, file)) = cue::sidecar(path) {
+            sheet.apply(&mut metadata, file);
+        }
+        if metadata.artwork.is_none() {
+            metadata.artwork = folder_artwork(path);
+        }
+        Ok(metadata)
+    }
+
+    /// The innermost chapter playing at `position`.
+    pub fn chapter_at(&self, position: time::Duration) -> Option<usize> {
+        self.chapters
+            .iter()
+            .rposition(|chapter| chapter.start <= position && position < chapter.end)
+    }
+
+    /// Metadata with nothing known yet, for extractors to fill in.
+    pub fn empty(path: &path::PathBuf) -> Metadata {
+        Metadata {
+            path: path.clone(),
+            title: String::new(),
+            ..Default::default()
+        }
+    }
+
+    /// Whether anything is left that a later extractor could add.
+    fn is_complete(&self) -> bool {
+        !self.title.is_empty()
+            && !self.artist.is_empty()
+            && !self.album.is_empty()
+            && self.duration > time::Duration::from_secs(0)
+            && !self.chapters.is_empty()
+            && self.artwork.is_some()
+    }
+
+    /// Takes whatever this metadata lacks from a source of lower priority.
+    fn fill(&mut self, other: Metadata) {
+        if self.title.is_empty() {
+            self.title = other.title;
+        }
+        if self.artist.is_empty() {
+            self.artist = other.artist;
+        }
+        if self.album.is_empty() {
+            self.album = other.album;
+        }
+        if self.narrator.is_empty() {
+            self.narrator = other.narrator;
+        }
+        if self.duration == time::Duration::from_secs(0) {
+            self.duration = other.duration;
+        }
+        if self.chapters.is_empty() {
+            self.chapters = other.chapters;
+        }
+        if self.artwork.is_none() {
+            self.artwork = other.artwork;
+        }
+    }
+}
+
+impl Default for Metadata {
+    fn default() -> Self {
+        Metadata {
+            path: path::PathBuf::new(),
+            chapters: vec![],
+            title: "None".to_string(),
+            artist: "".to_string(),
+            album: "".to_string(),
+            narrator: "".to_string(),
+            duration: time::Duration::from_secs(0),
+            artwork: None,
+        }
+    }
+}
+
+pub fn units_to_time(units: u64, timescale: u32) -> time::Duration {
+    let timescale = timescale.max(1) as u64;
+    let nanos = (units % timescale) * 1_000_000_000 / timescale;
+    time::Duration::new(units / timescale, nanos as u32)
+}
+
+/// Turns a list of chapter start marks into chapters, each one ending where
+/// the next begins and the last one at the end of the file.
+pub fn chapters_from_marks(
+    mut marks: Vec<(String, time::Duration)>,
+    duration: time::Duration,
+) -> Vec<Chapter> {
+    marks.sort_by_key(|&(_, start)| start);
+
+    let ends: Vec<time::Duration> = marks
+        .iter()
+        .skip(1)
+        .map(|&(_, start)| start)
+        .chain(Some(duration))
+        .collect();
+
+    marks
+        .into_iter()
+        .zip(ends.into_iter())
+        .map(|((title, start), end)| Chapter {
+            title,
+            start,
+            end: end.max(start),
+            artwork: None,
+            parent: None,
+            depth: 0,
+        })
+        .collect()
+}
+
+/// Looks for an image such as `cover.jpg` or `Folder.png` next to a file.
+fn folder_artwork(path: &path::Path) -> Option<Artwork> {
+    let mut images: Vec<(usize, path::PathBuf, &'static str)> = fs::read_dir(path.parent()?)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter_map(|image| {
+            let stem = image.file_stem()?.to_str()?.to_lowercase();
+            let rank = FOLDER_IMAGES.iter().position(|name| *name == stem)?;
+            let mime = match image.extension()?.to_str()?.to_lowercase().as_str() {
+                "jpg" | "jpeg" => "image/jpeg",
+                "png" => "image/png",
+                _ => return None,
+            };
+            Some((rank, image, mime))
+        })
+        .collect();
+    images.sort_by_key(|&(rank, _, _)| rank);
+
+    let (_, image, mime) = images.into_iter().next()?;
+    Some(Artwork {
+        mime: mime.to_string(),
+        data: fs::read(image).ok()?,
+    })
+}
+
+fn read_header(path: &path::PathBuf) -> Result<Vec<u8>> {
+    let mut header = Vec::with_capacity(16);
+    fs::File::open(path)?.take(16).read_to_end(&mut header)?;
+    Ok(header)
+}
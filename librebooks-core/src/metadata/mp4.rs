@@ -0,0 +1,619 @@
+use std::fs;
+use std::io::{Read, Seek, SeekFrom};
+use std::path;
+use std::time;
+
+use byteorder::{BigEndian, ByteOrder, ReadBytesExt};
+
+use errors::{ErrorKind, Result};
+use metadata::{chapters_from_marks, units_to_time, Artwork, Metadata, MetadataExtractor};
+
+type Kind = [u8; 4];
+
+const NERO_TIMESCALE: u32 = 10_000_000;
+
+/// Most samples read from a chapter track, far more than any book has
+/// chapters. Sample tables can claim billions without taking up space.
+const MAX_CHAPTER_SAMPLES: usize = 10_000;
+
+/// A text sample is a 16-bit length followed by that many bytes.
+const MAX_TEXT_SAMPLE: u32 = 2 + 0xffff;
+
+struct Atom<'a> {
+    kind: Kind,
+    data: &'a [u8],
+}
+
+fn malformed<S: Into<String>>(reason: S) -> ErrorKind {
+    ErrorKind::MalformedMedia("mp4", reason.into())
+}
+
+pub struct Mp4;
+
+impl MetadataExtractor for Mp4 {
+    fn sniff(&self, header: &[u8]) -> bool {
+        header.len() >= 8 && &header[4..8] == b"ftyp"
+    }
+
+    fn extract(&self, path: &path::PathBuf) -> Result<Metadata> {
+        read(path)
+    }
+}
+
+fn read(path: &path::PathBuf) -> Result<Metadata> {
+    let mut file = fs::File::open(path)?;
+    let length = file.metadata()?.len();
+    let moov = read_moov(&mut file, length)?;
+
+    let (timescale, units) = match find(&moov, &[b"mvhd"])? {
+        Some(mvhd) => header_times(mvhd)?,
+        None => bail!(malformed("missing mvhd atom")),
+    };
+    let duration = units_to_time(units, timescale);
+
+    let mut title = None;
+    let mut artist = None;
+    let mut album = None;
+    let mut narrator = None;
+    let mut artwork = None;
+
+    if let Some(ilst) = find_ilst(&moov)? {
+        for item in atoms(ilst)? {
+            if &item.kind == b"covr" {
+                artwork = item_picture(item.data)?;
+                continue;
+            }
+            let value = match item_text(item.data)? {
+                Some(value) => value,
+                None => continue,
+            };
+            match &item.kind {
+                b"\xa9nam" => title = Some(value),
+                b"\xa9ART" | b"aART" if artist.is_none() => artist = Some(value),
+                b"\xa9alb" => album = Some(value),
+                b"\xa9wrt" => narrator = Some(value),
+                _ => {}
+            }
+        }
+    }
+
+    let mut marks = quicktime_chapters(&mut file, length, &moov)?;
+    if marks.is_empty() {
+        if let Some(chpl) = find(&moov, &[b"udta", b"chpl"])? {
+            marks = nero_chapters(chpl)?;
+        }
+    }
+
+    Ok(Metadata {
+        path: path.clone(),
+        title: title.unwrap_or_default(),
+        artist: artist.unwrap_or_default(),
+        album: album.unwrap_or_default(),
+        narrator: narrator.unwrap_or_default(),
+        duration,
+        chapters: chapters_from_marks(marks, duration),
+        artwork,
+    })
+}
+
+fn read_moov(file: &mut fs::File, length: u64) -> Result<Vec<u8>> {
+    let mut offset = 0;
+
+    while offset + 8 <= length {
+        file.seek(SeekFrom::Start(offset))?;
+        let mut size = file.read_u32::<BigEndian>()? as u64;
+        let mut kind = [0; 4];
+        file.read_exact(&mut kind)?;
+
+        let mut header = 8;
+        if size == 1 {
+            size = file.read_u64::<BigEndian>()?;
+            header = 16;
+        } else if size == 0 {
+            size = length - offset;
+        }
+
+        let end = match offset.checked_add(size) {
+            Some(end) if size >= header && end <= length => end,
+            _ => bail!(malformed(format!("bad size for top-level atom at {}", offset))),
+        };
+
+        if &kind == b"moov" {
+            let mut moov = vec![0; (size - header) as usize];
+            file.read_exact(&mut moov)?;
+            return Ok(moov);
+        }
+
+        offset = end;
+    }
+
+    bail!(malformed("missing moov atom"))
+}
+
+fn atoms(data: &[u8]) -> Result<Vec<Atom>> {
+    let mut atoms = vec![];
+    let mut rest = data;
+
+    while rest.len() >= 8 {
+        let mut size = BigEndian::read_u32(&rest[0..4]) as u64;
+        let mut kind = [0; 4];
+        kind.copy_from_slice(&rest[4..8]);
+
+        let mut header = 8;
+        if size == 1 {
+            if rest.len() < 16 {
+                bail!(malformed("truncated atom header"));
+            }
+            size = BigEndian::read_u64(&rest[8..16]);
+            header = 16;
+        } else if size == 0 {
+            size = rest.len() as u64;
+        }
+
+        if size < header as u64 || size > rest.len() as u64 {
+            bail!(malformed(format!(
+                "atom {} overruns its parent",
+                String::from_utf8_lossy(&kind)
+            )));
+        }
+
+        let (atom, tail) = rest.split_at(size as usize);
+        atoms.push(Atom {
+            kind,
+            data: &atom[header..],
+        });
+        rest = tail;
+    }
+
+    Ok(atoms)
+}
+
+fn find<'a>(data: &'a [u8], path: &[&Kind]) -> Result<Option<&'a [u8]>> {
+    let mut current = data;
+    for kind in path {
+        match atoms(current)?.into_iter().find(|atom| &atom.kind == *kind) {
+            Some(atom) => current = atom.data,
+            None => return Ok(None),
+        }
+    }
+    Ok(Some(current))
+}
+
+fn find_ilst(moov: &[u8]) -> Result<Option<&[u8]>> {
+    let meta = match find(moov, &[b"udta", b"meta"])? {
+        Some(meta) => meta,
+        None => return Ok(None),
+    };
+
+    // ISO meta atoms are full boxes, QuickTime ones start straight with hdlr.
+    let children = if meta.len() >= 8 && &meta[4..8] == b"hdlr" {
+        meta
+    } else if meta.len() >= 4 {
+        &meta[4..]
+    } else {
+        return Ok(None);
+    };
+
+    find(children, &[b"ilst"])
+}
+
+fn item_text(item: &[u8]) -> Result<Option<String>> {
+    for atom in atoms(item)? {
+        if &atom.kind != b"data" || atom.data.len() < 8 {
+            continue;
+        }
+        let value = &atom.data[8..];
+        let text = match BigEndian::read_u32(&atom.data[0..4]) & 0x00ff_ffff {
+            1 => String::from_utf8_lossy(value).into_owned(),
+            2 => utf16_text(value),
+            _ => continue,
+        };
+        return Ok(Some(text));
+    }
+    Ok(None)
+}
+
+/// The first image of a `covr` item, typed as JPEG (13) or PNG (14).
+fn item_picture(item: &[u8]) -> Result<Option<Artwork>> {
+    for atom in atoms(item)? {
+        if &atom.kind != b"data" || atom.data.len() < 8 {
+            continue;
+        }
+        let mime = match BigEndian::read_u32(&atom.data[0..4]) & 0x00ff_ffff {
+            13 => "image/jpeg",
+            14 => "image/png",
+            _ => continue,
+        };
+        return Ok(Some(Artwork {
+            mime: mime.to_string(),
+            data: atom.data[8..].to_vec(),
+        }));
+    }
+    Ok(None)
+}
+
+/// Timescale and duration from an `mvhd` or `mdhd` payload.
+fn header_times(data: &[u8]) -> Result<(u32, u64)> {
+    let mut cursor = data;
+    let version = cursor.read_u8()?;
+    cursor.read_u24::<BigEndian>()?;
+
+    if version == 1 {
+        cursor.read_u64::<BigEndian>()?;
+        cursor.read_u64::<BigEndian>()?;
+        let timescale = cursor.read_u32::<BigEndian>()?;
+        Ok((timescale, cursor.read_u64::<BigEndian>()?))
+    } else {
+        cursor.read_u32::<BigEndian>()?;
+        cursor.read_u32::<BigEndian>()?;
+        let timescale = cursor.read_u32::<BigEndian>()?;
+        Ok((timescale, cursor.read_u32::<BigEndian>()? as u64))
+    }
+}
+
+fn track_id(tkhd: &[u8]) -> Result<u32> {
+    let mut cursor = tkhd;
+    let version = cursor.read_u8()?;
+    cursor.read_u24::<BigEndian>()?;
+
+    if version == 1 {
+        cursor.read_u64::<BigEndian>()?;
+        cursor.read_u64::<BigEndian>()?;
+    } else {
+        cursor.read_u32::<BigEndian>()?;
+        cursor.read_u32::<BigEndian>()?;
+    }
+    Ok(cursor.read_u32::<BigEndian>()?)
+}
+
+fn nero_chapters(chpl: &[u8]) -> Result<Vec<(String, time::Duration)>> {
+    let mut cursor = chpl;
+    let version = cursor.read_u8()?;
+    cursor.read_u24::<BigEndian>()?;
+    if version > 0 {
+        cursor.read_u32::<BigEndian>()?;
+    }
+
+    let count = cursor.read_u8()?;
+    let mut marks = vec![];
+    for _ in 0..count {
+        let start = cursor.read_u64::<BigEndian>()?;
+        let length = cursor.read_u8()? as usize;
+        if cursor.len() < length {
+            bail!(malformed("truncated chpl title"));
+        }
+        let title = String::from_utf8_lossy(&cursor[..length]).into_owned();
+        cursor = &cursor[length..];
+        marks.push((title, units_to_time(start, NERO_TIMESCALE)));
+    }
+    Ok(marks)
+}
+
+fn quicktime_chapters(
+    file: &mut fs::File,
+    length: u64,
+    moov: &[u8],
+) -> Result<Vec<(String, time::Duration)>> {
+    let traks: Vec<&[u8]> = atoms(moov)?
+        .into_iter()
+        .filter(|atom| &atom.kind == b"trak")
+        .map(|atom| atom.data)
+        .collect();
+
+    let mut chapter_tracks = vec![];
+    for trak in traks.iter() {
+        if let Some(chap) = find(trak, &[b"tref", b"chap"])? {
+            let mut cursor = chap;
+            while cursor.len() >= 4 {
+                chapter_tracks.push(cursor.read_u32::<BigEndian>()?);
+            }
+        }
+    }
+
+    for trak in traks.iter() {
+        let id = match find(trak, &[b"tkhd"])? {
+            Some(tkhd) => track_id(tkhd)?,
+            None => continue,
+        };
+        if chapter_tracks.contains(&id) {
+            return text_samples(file, length, trak);
+        }
+    }
+
+    Ok(vec![])
+}
+
+fn text_samples(
+    file: &mut fs::File,
+    length: u64,
+    trak: &[u8],
+) -> Result<Vec<(String, time::Duration)>> {
+    let timescale = match find(trak, &[b"mdia", b"mdhd"])? {
+        Some(mdhd) => header_times(mdhd)?.0,
+        None => bail!(malformed("chapter track without mdhd")),
+    };
+    let stbl = match find(trak, &[b"mdia", b"minf", b"stbl"])? {
+        Some(stbl) => stbl,
+        None => bail!(malformed("chapter track without stbl")),
+    };
+
+    let offsets = sample_offsets(stbl, length, MAX_CHAPTER_SAMPLES)?;
+    let starts = sample_starts(stbl, offsets.len())?;
+
+    let mut marks = vec![];
+    for (start, (offset, size)) in starts.into_iter().zip(offsets.into_iter()) {
+        if size < 2 {
+            continue;
+        }
+        match offset.checked_add(size as u64) {
+            Some(end) if end <= length => {}
+            _ => bail!(malformed("chapter sample past the end of the file")),
+        }
+        let mut sample = vec![0; size.min(MAX_TEXT_SAMPLE) as usize];
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut sample)?;
+
+        let length = BigEndian::read_u16(&sample[0..2]) as usize;
+        let text = &sample[2..(2 + length).min(sample.len())];
+        let title = if text.starts_with(&[0xfe, 0xff]) {
+            utf16_text(&text[2..])
+        } else {
+            String::from_utf8_lossy(text).into_owned()
+        };
+        marks.push((title, units_to_time(start, timescale)));
+    }
+    Ok(marks)
+}
+
+/// Start of the first `limit` samples, in units of the track timescale.
+fn sample_starts(stbl: &[u8], limit: usize) -> Result<Vec<u64>> {
+    let mut cursor = match find(stbl, &[b"stts"])? {
+        Some(stts) => stts,
+        None => bail!(malformed("missing stts atom")),
+    };
+    cursor.read_u32::<BigEndian>()?;
+
+    let mut starts = vec![];
+    let mut position: u64 = 0;
+    let entries = table_entries(&mut cursor, 8)?;
+    for _ in 0..entries {
+        let count = cursor.read_u32::<BigEndian>()?;
+        let delta = cursor.read_u32::<BigEndian>()? as u64;
+        for _ in 0..count {
+            if starts.len() >= limit {
+                return Ok(starts);
+            }
+            starts.push(position);
+            position = match position.checked_add(delta) {
+                Some(position) => position,
+                None => bail!(malformed("sample times overflow")),
+            };
+        }
+    }
+    Ok(starts)
+}
+
+/// File offset and size of the first `limit` samples, resolved through
+/// stsc/stco/stsz. Samples have to fit in a file of `length` bytes.
+fn sample_offsets(stbl: &[u8], length: u64, limit: usize) -> Result<Vec<(u64, u32)>> {
+    let sizes = {
+        let mut cursor = match find(stbl, &[b"stsz"])? {
+            Some(stsz) => stsz,
+            None => bail!(malformed("missing stsz atom")),
+        };
+        cursor.read_u32::<BigEndian>()?;
+        let size = cursor.read_u32::<BigEndian>()?;
+        if size == 0 {
+            let count = table_entries(&mut cursor, 4)?;
+            let mut sizes = vec![];
+            for _ in 0..count.min(limit) {
+                sizes.push(cursor.read_u32::<BigEndian>()?);
+            }
+            sizes
+        } else {
+            // Samples of one size are not listed, but no more of them fit
+            // in the file than its length allows.
+            let count = cursor.read_u32::<BigEndian>()? as u64;
+            let count = count.min(length / size as u64).min(limit as u64);
+            vec![size; count as usize]
+        }
+    };
+
+    let chunks = if let Some(mut cursor) = find(stbl, &[b"stco"])? {
+        cursor.read_u32::<BigEndian>()?;
+        let mut chunks = vec![];
+        for _ in 0..table_entries(&mut cursor, 4)? {
+            chunks.push(cursor.read_u32::<BigEndian>()? as u64);
+        }
+        chunks
+    } else if let Some(mut cursor) = find(stbl, &[b"co64"])? {
+        cursor.read_u32::<BigEndian>()?;
+        let mut chunks = vec![];
+        for _ in 0..table_entries(&mut cursor, 8)? {
+            chunks.push(cursor.read_u64::<BigEndian>()?);
+        }
+        chunks
+    } else {
+        bail!(malformed("missing chunk offsets"))
+    };
+
+    let runs = {
+        let mut cursor = match find(stbl, &[b"stsc"])? {
+            Some(stsc) => stsc,
+            None => bail!(malformed("missing stsc atom")),
+        };
+        cursor.read_u32::<BigEndian>()?;
+        let mut runs = vec![];
+        for _ in 0..table_entries(&mut cursor, 12)? {
+            let first_chunk = cursor.read_u32::<BigEndian>()? as usize;
+            let samples = cursor.read_u32::<BigEndian>()?;
+            cursor.read_u32::<BigEndian>()?;
+            runs.push((first_chunk, samples));
+        }
+        runs
+    };
+
+    let mut offsets = vec![];
+    let mut sample = 0;
+    for (index, chunk) in chunks.iter().enumerate() {
+        if sample >= sizes.len() {
+            break;
+        }
+        let per_chunk = runs
+            .iter()
+            .take_while(|&&(first_chunk, _)| first_chunk <= index + 1)
+            .last()
+            .map(|&(_, samples)| samples)
+            .unwrap_or(0);
+
+        let mut offset = *chunk;
+        for _ in 0..per_chunk {
+            if sample >= sizes.len() {
+                return Ok(offsets);
+            }
+            offsets.push((offset, sizes[sample]));
+            offset = match offset.checked_add(sizes[sample] as u64) {
+                Some(offset) => offset,
+                None => bail!(malformed("chunk runs past the end of the file")),
+            };
+            sample += 1;
+        }
+    }
+    Ok(offsets)
+}
+
+/// Reads the entry count of a sample table, which has to leave room for
+/// that many entries of `size` bytes in the rest of the atom.
+fn table_entries(cursor: &mut &[u8], size: usize) -> Result<usize> {
+    let count = cursor.read_u32::<BigEndian>()? as usize;
+    match count.checked_mul(size) {
+        Some(bytes) if bytes <= cursor.len() => Ok(count),
+        _ => bail!(malformed("sample table overruns its atom")),
+    }
+}
+
+fn utf16_text(data: &[u8]) -> String {
+    let units: Vec<u16> = data
+        .chunks(2)
+        .filter(|pair| pair.len() == 2)
+        .map(|pair| BigEndian::read_u16(pair))
+        .collect();
+    String::from_utf16_lossy(&units)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::io::Write;
+    use std::path;
+
+    use byteorder::{BigEndian, WriteBytesExt};
+
+    use super::{read, sample_offsets, sample_starts, MAX_CHAPTER_SAMPLES};
+
+    fn atom(kind: &[u8], body: &[u8]) -> Vec<u8> {
+        let mut atom = vec![];
+        atom.write_u32::<BigEndian>(body.len() as u32 + 8).unwrap();
+        atom.extend_from_slice(kind);
+        atom.extend_from_slice(body);
+        atom
+    }
+
+    /// A full box: version and flags, then 32-bit fields.
+    fn table(kind: &[u8], fields: &[u32]) -> Vec<u8> {
+        let mut body = vec![0; 4];
+        for &field in fields {
+            body.write_u32::<BigEndian>(field).unwrap();
+        }
+        atom(kind, &body)
+    }
+
+    fn write(name: &str, data: &[u8]) -> path::PathBuf {
+        let path = env::temp_dir().join(format!("librebooks-mp4-{}", name));
+        fs::File::create(&path).unwrap().write_all(data).unwrap();
+        path
+    }
+
+    #[test]
+    fn stops_counting_samples_at_the_limit() {
+        let stts = table(b"stts", &[2, 0xffff_ffff, 1, 0xffff_ffff, 0xffff_ffff]);
+        let starts = sample_starts(&stts, MAX_CHAPTER_SAMPLES).unwrap();
+        assert_eq!(starts.len(), MAX_CHAPTER_SAMPLES);
+        assert_eq!(starts[9], 9);
+    }
+
+    #[test]
+    fn rejects_tables_longer_than_their_atom() {
+        let stts = table(b"stts", &[0x1000_0000, 1, 1]);
+        assert!(sample_starts(&stts, MAX_CHAPTER_SAMPLES).is_err());
+
+        let mut stbl = table(b"stsz", &[0, 0x1000_0000, 10]);
+        stbl.extend(table(b"stco", &[1, 0]));
+        stbl.extend(table(b"stsc", &[1, 1, 1, 1]));
+        assert!(sample_offsets(&stbl, 1000, MAX_CHAPTER_SAMPLES).is_err());
+    }
+
+    #[test]
+    fn fits_samples_of_one_size_in_the_file() {
+        let mut stbl = table(b"stsz", &[100, 0xffff_ffff]);
+        stbl.extend(table(b"stco", &[1, 0]));
+        stbl.extend(table(b"stsc", &[1, 1, 0xffff_ffff, 1]));
+        let offsets = sample_offsets(&stbl, 1000, MAX_CHAPTER_SAMPLES).unwrap();
+        assert_eq!(offsets.len(), 10);
+        assert_eq!(offsets[9], (900, 100));
+    }
+
+    #[test]
+    fn rejects_chunks_that_overflow() {
+        let mut stbl = table(b"stsz", &[0, 2, 0xffff_ffff, 0xffff_ffff]);
+        let mut co64 = vec![0; 4];
+        co64.write_u32::<BigEndian>(1).unwrap();
+        co64.write_u64::<BigEndian>(u64::max_value() - 10).unwrap();
+        stbl.extend(atom(b"co64", &co64));
+        stbl.extend(table(b"stsc", &[1, 1, 2, 1]));
+        assert!(sample_offsets(&stbl, 1000, MAX_CHAPTER_SAMPLES).is_err());
+    }
+
+    #[test]
+    fn rejects_top_level_atoms_that_overflow() {
+        let mut file = atom(b"ftyp", b"M4B \0\0\0\0");
+        file.write_u32::<BigEndian>(1).unwrap();
+        file.extend_from_slice(b"mdat");
+        file.write_u64::<BigEndian>(u64::max_value()).unwrap();
+        file.extend_from_slice(&[0; 16]);
+        let path = write("overflow.m4b", &file);
+        assert!(read(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rejects_chapter_samples_past_the_end() {
+        let mut mvhd = vec![0; 12];
+        mvhd.write_u32::<BigEndian>(1000).unwrap();
+        mvhd.write_u32::<BigEndian>(60_000).unwrap();
+
+        let mut stbl = table(b"stts", &[1, 1, 1000]);
+        stbl.extend(table(b"stsz", &[0, 1, 100]));
+        stbl.extend(table(b"stco", &[1, 0xffff_0000]));
+        stbl.extend(table(b"stsc", &[1, 1, 1, 1]));
+        let minf = atom(b"minf", &atom(b"stbl", &stbl));
+        let mut mdia = table(b"mdhd", &[0, 0, 1000, 60_000]);
+        mdia.extend(minf);
+
+        let mut text = table(b"tkhd", &[0, 0, 2]);
+        text.extend(atom(b"mdia", &mdia));
+        let mut audio = table(b"tkhd", &[0, 0, 1]);
+        audio.extend(atom(b"tref", &atom(b"chap", &[0, 0, 0, 2])));
+
+        let mut moov = atom(b"mvhd", &mvhd);
+        moov.extend(atom(b"trak", &audio));
+        moov.extend(atom(b"trak", &text));
+        let mut file = atom(b"ftyp", b"M4B \0\0\0\0");
+        file.extend(atom(b"moov", &moov));
+
+        let path = write("samples.m4b", &file);
+        assert!(read(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
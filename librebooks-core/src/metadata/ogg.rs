@@ -0,0 +1,233 @@
+use std::fs;
+use std::io::{Read, Seek, SeekFrom};
+use std::path;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use errors::{ErrorKind, Result};
+use metadata::{units_to_time, vorbis, Metadata, MetadataExtractor};
+
+const PAGE_HEADER_LEN: usize = 27;
+
+/// How much of the end of the file is searched for the last page, which is
+/// at most 64 KiB long.
+const TAIL_LEN: u64 = 128 * 1024;
+
+/// Opus granule positions always count 48 kHz samples.
+const OPUS_RATE: u32 = 48_000;
+
+/// Generator polynomial of the page checksum, a CRC-32 without reflection,
+/// initial value or final xor.
+const CRC_POLYNOMIAL: u32 = 0x04c1_1db7;
+
+fn malformed<S: Into<String>>(reason: S) -> ErrorKind {
+    ErrorKind::MalformedMedia("ogg", reason.into())
+}
+
+/// Ogg Vorbis and Ogg Opus, whose tags are Vorbis comments.
+pub struct Ogg;
+
+impl MetadataExtractor for Ogg {
+    fn sniff(&self, header: &[u8]) -> bool {
+        header.starts_with(b"OggS")
+    }
+
+    fn extract(&self, path: &path::PathBuf) -> Result<Metadata> {
+        read(path)
+    }
+}
+
+struct Page {
+    serial: u32,
+    /// Lacing values, a packet ends at the first one below 255.
+    segments: Vec<u8>,
+    data: Vec<u8>,
+}
+
+fn read(path: &path::PathBuf) -> Result<Metadata> {
+    let mut file = fs::File::open(path)?;
+    let (serial, packets) = header_packets(&mut file)?;
+
+    let (rate, pre_skip, comments) = if packets[0].starts_with(b"\x01vorbis") {
+        if packets[0].len() < 16 || !packets[1].starts_with(b"\x03vorbis") {
+            bail!(malformed("truncated Vorbis headers"));
+        }
+        (LittleEndian::read_u32(&packets[0][12..16]), 0, &packets[1][7..])
+    } else if packets[0].starts_with(b"OpusHead") {
+        if packets[0].len() < 12 || !packets[1].starts_with(b"OpusTags") {
+            bail!(malformed("truncated Opus headers"));
+        }
+        let pre_skip = LittleEndian::read_u16(&packets[0][10..12]) as u64;
+        (OPUS_RATE, pre_skip, &packets[1][8..])
+    } else {
+        bail!(malformed("unsupported codec"));
+    };
+
+    let mut metadata = Metadata::empty(path);
+    if let Some(granule) = last_granule(&mut file, serial)? {
+        metadata.duration = units_to_time(granule.saturating_sub(pre_skip), rate);
+    }
+    vorbis::apply(&mut metadata, vorbis::comments(comments)?);
+    Ok(metadata)
+}
+
+/// The identification and comment packets of the first stream in the file.
+fn header_packets(file: &mut fs::File) -> Result<(u32, Vec<Vec<u8>>)> {
+    let first = read_page(file)?;
+    let serial = first.serial;
+
+    let mut packets = vec![];
+    let mut packet = vec![];
+    let mut page = Some(first);
+    while packets.len() < 2 {
+        let current = match page.take() {
+            Some(current) => current,
+            None => read_page(file)?,
+        };
+        if current.serial != serial {
+            continue;
+        }
+
+        let mut offset = 0;
+        for &lacing in &current.segments {
+            let end = offset + lacing as usize;
+            if end > current.data.len() {
+                bail!(malformed("page shorter than its segments"));
+            }
+            packet.extend_from_slice(&current.data[offset..end]);
+            offset = end;
+            if lacing < 255 {
+                packets.push(packet);
+                packet = vec![];
+            }
+        }
+    }
+    Ok((serial, packets))
+}
+
+fn read_page(file: &mut fs::File) -> Result<Page> {
+    let mut header = [0; PAGE_HEADER_LEN];
+    file.read_exact(&mut header)?;
+    if !header.starts_with(b"OggS") {
+        bail!(malformed("lost page sync"));
+    }
+
+    let mut segments = vec![0; header[26] as usize];
+    file.read_exact(&mut segments)?;
+    let length = segments.iter().map(|&lacing| lacing as usize).sum();
+    let mut data = vec![0; length];
+    file.read_exact(&mut data)?;
+
+    Ok(Page {
+        serial: LittleEndian::read_u32(&header[14..18]),
+        segments,
+        data,
+    })
+}
+
+/// Granule position of the last page of a stream, which is the number of
+/// samples in it.
+fn last_granule(file: &mut fs::File, serial: u32) -> Result<Option<u64>> {
+    let length = file.metadata()?.len();
+    let start = length.saturating_sub(TAIL_LEN);
+    file.seek(SeekFrom::Start(start))?;
+    let mut tail = vec![];
+    file.take(TAIL_LEN).read_to_end(&mut tail)?;
+    Ok(tail_granule(&tail, serial))
+}
+
+fn tail_granule(tail: &[u8], serial: u32) -> Option<u64> {
+    let mut last = None;
+    let mut offset = 0;
+    while offset + PAGE_HEADER_LEN <= tail.len() {
+        let length = match page_at(tail, offset) {
+            Some(length) => length,
+            None => {
+                offset += 1;
+                continue;
+            }
+        };
+        let header = &tail[offset..offset + PAGE_HEADER_LEN];
+        let granule = LittleEndian::read_i64(&header[6..14]);
+        if LittleEndian::read_u32(&header[14..18]) == serial && granule >= 0 {
+            last = Some(granule as u64);
+        }
+        offset += length;
+    }
+    last
+}
+
+/// Length of the page at `offset`, if a whole one whose checksum holds
+/// starts there. Audio data can contain "OggS" as well.
+fn page_at(data: &[u8], offset: usize) -> Option<usize> {
+    let header = data.get(offset..offset + PAGE_HEADER_LEN)?;
+    if !header.starts_with(b"OggS") || header[4] != 0 {
+        return None;
+    }
+    let segments = offset + PAGE_HEADER_LEN..offset + PAGE_HEADER_LEN + header[26] as usize;
+    let body: usize = data.get(segments.clone())?.iter().map(|&lacing| lacing as usize).sum();
+    let page = data.get(offset..segments.end + body)?;
+    if page_crc(page) != LittleEndian::read_u32(&header[22..26]) {
+        return None;
+    }
+    Some(page.len())
+}
+
+/// Checksum of a page, the checksum field itself counting as zeros.
+fn page_crc(page: &[u8]) -> u32 {
+    let mut crc = 0u32;
+    for (index, &byte) in page.iter().enumerate() {
+        let byte = if index >= 22 && index < 26 { 0 } else { byte };
+        crc ^= (byte as u32) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ CRC_POLYNOMIAL
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use byteorder::{ByteOrder, LittleEndian};
+
+    use super::{page_crc, tail_granule};
+
+    fn page(serial: u32, granule: i64, data: &[u8]) -> Vec<u8> {
+        let mut page = b"OggS\0\0".to_vec();
+        page.extend_from_slice(&[0; 21]);
+        LittleEndian::write_i64(&mut page[6..14], granule);
+        LittleEndian::write_u32(&mut page[14..18], serial);
+        page[26] = 1;
+        page.push(data.len() as u8);
+        page.extend_from_slice(data);
+        let crc = page_crc(&page);
+        LittleEndian::write_u32(&mut page[22..26], crc);
+        page
+    }
+
+    #[test]
+    fn computes_the_ogg_checksum() {
+        assert_eq!(page_crc(b"123456789"), 0x89a1_897f);
+    }
+
+    #[test]
+    fn takes_the_granule_of_the_last_valid_page() {
+        let mut tail = page(7, 1000, &[1, 2, 3]);
+        tail.extend(page(7, 2000, &[4, 5, 6]));
+        assert_eq!(tail_granule(&tail, 7), Some(2000));
+        assert_eq!(tail_granule(&tail, 8), None);
+    }
+
+    #[test]
+    fn ignores_capture_patterns_in_audio_data() {
+        let mut fake = page(7, 9_000_000, &[]);
+        fake[22] ^= 0xff;
+        let mut tail = page(7, 1000, &fake);
+        tail.extend(fake);
+        assert_eq!(tail_granule(&tail, 7), Some(1000));
+    }
+}
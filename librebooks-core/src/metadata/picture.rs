@@ -0,0 +1,51 @@
+use byteorder::{BigEndian, ReadBytesExt};
+
+use metadata::Artwork;
+
+/// Picture type of the front cover, the same in FLAC and ID3v2.
+pub const FRONT_COVER: u32 = 3;
+
+/// Picks the front cover out of typed pictures, or the first one if there is
+/// no front cover.
+pub fn cover(mut pictures: Vec<(u32, Artwork)>) -> Option<Artwork> {
+    let front = pictures.iter().position(|&(kind, _)| kind == FRONT_COVER);
+    match front {
+        Some(index) => Some(pictures.swap_remove(index).1),
+        None if !pictures.is_empty() => Some(pictures.swap_remove(0).1),
+        None => None,
+    }
+}
+
+/// Parses a PICTURE block, the same layout is base64 encoded in Vorbis
+/// comments.
+pub fn picture(data: &[u8]) -> Option<(u32, Artwork)> {
+    let mut cursor = data;
+    let kind = cursor.read_u32::<BigEndian>().ok()?;
+
+    let length = cursor.read_u32::<BigEndian>().ok()? as usize;
+    if cursor.len() < length {
+        return None;
+    }
+    let mime = String::from_utf8_lossy(&cursor[..length]).into_owned();
+    cursor = &cursor[length..];
+
+    let length = cursor.read_u32::<BigEndian>().ok()? as usize;
+    if cursor.len() < length + 16 {
+        return None;
+    }
+    // Description, then width, height, depth and palette size.
+    cursor = &cursor[length + 16..];
+
+    let length = cursor.read_u32::<BigEndian>().ok()? as usize;
+    if cursor.len() < length {
+        return None;
+    }
+
+    Some((
+        kind,
+        Artwork {
+            mime,
+            data: cursor[..length].to_vec(),
+        },
+    ))
+}
@@ -0,0 +1,141 @@
+use std::time;
+
+use metadata::{Chapter, Metadata};
+
+/// First words of part titles, a part holds the chapters that follow it.
+const PART_WORDS: &[&str] = &["part", "book", "volume", "teil", "partie", "parte"];
+
+/// Spelled out numbers that may follow a part word.
+const NUMBER_WORDS: &[&str] = &[
+    "one", "two", "three", "four", "five", "six", "seven", "eight", "nine", "ten", "eleven",
+    "twelve", "first", "second", "third", "fourth", "fifth", "last",
+];
+
+impl Metadata {
+    /// How deeply the chapters nest, 0 when they are flat.
+    pub fn depth(&self) -> usize {
+        self.chapters
+            .iter()
+            .map(|chapter| chapter.depth)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// The chapters that make up the book at a level of the tree: those at
+    /// that level, and shallower ones that have no chapters of their own.
+    pub fn chapters_at_level(&self, level: usize) -> Vec<usize> {
+        let mut has_children = vec![false; self.chapters.len()];
+        for chapter in self.chapters.iter() {
+            if let Some(parent) = chapter.parent {
+                if parent < has_children.len() {
+                    has_children[parent] = true;
+                }
+            }
+        }
+
+        let mut chapters: Vec<usize> = (0..self.chapters.len())
+            .filter(|&index| {
+                let depth = self.chapters[index].depth;
+                depth == level || (depth < level && !has_children[index])
+            })
+            .collect();
+        chapters.sort_by_key(|&index| self.chapters[index].start);
+        chapters
+    }
+
+    /// Start of the chapter after `position` at a level of the tree.
+    pub fn next_chapter(&self, position: time::Duration, level: usize) -> Option<time::Duration> {
+        self.chapters_at_level(level)
+            .into_iter()
+            .map(|index| self.chapters[index].start)
+            .find(|&start| start > position)
+    }
+
+    /// Start of the chapter playing at `position` at a level of the tree, or
+    /// of the one before when less than `grace` of it has been played.
+    pub fn previous_chapter(
+        &self,
+        position: time::Duration,
+        level: usize,
+        grace: time::Duration,
+    ) -> Option<time::Duration> {
+        let starts: Vec<time::Duration> = self
+            .chapters_at_level(level)
+            .into_iter()
+            .map(|index| self.chapters[index].start)
+            .collect();
+        let current = starts.iter().rposition(|&start| start <= position)?;
+
+        if current == 0 || position - starts[current] >= grace {
+            Some(starts[current])
+        } else {
+            Some(starts[current - 1])
+        }
+    }
+}
+
+/// Nests flat chapters by their titles: parts such as "Part Two" hold the
+/// chapters after them, and "3.1" is a section of "3". Chapters that are
+/// already nested, or titles that show no structure, are left alone.
+pub fn nest_by_title(chapters: &mut Vec<Chapter>) {
+    if chapters.iter().any(|chapter| chapter.parent.is_some()) {
+        return;
+    }
+    let ranks: Vec<usize> = chapters.iter().map(|chapter| rank(&chapter.title)).collect();
+    if ranks.iter().all(|&rank| rank == ranks[0]) {
+        return;
+    }
+
+    let mut open: Vec<(usize, usize)> = vec![];
+    for (index, &rank) in ranks.iter().enumerate() {
+        while open.last().map_or(false, |&(_, parent)| parent >= rank) {
+            open.pop();
+        }
+        chapters[index].parent = open.last().map(|&(parent, _)| parent);
+        chapters[index].depth = open.len();
+        open.push((index, rank));
+    }
+
+    // A part lasts until its last chapter ends, children come after their
+    // parents so walking backwards carries ends all the way up.
+    for index in (0..chapters.len()).rev() {
+        if let Some(parent) = chapters[index].parent {
+            let end = chapters[index].end;
+            if chapters[parent].end < end {
+                chapters[parent].end = end;
+            }
+        }
+    }
+}
+
+/// Level a title suggests: 0 for parts, the number of components for
+/// numbered sections like "2.4", and 1 for everything else.
+fn rank(title: &str) -> usize {
+    let title = title.trim().to_lowercase();
+    let mut words = title.split_whitespace();
+    let first = words.next().unwrap_or("");
+    let second = words.next().unwrap_or("");
+
+    if PART_WORDS.contains(&first) && is_number(second) {
+        return 0;
+    }
+
+    let number = first.trim_right_matches(|c| c == '.' || c == ':' || c == ')');
+    let components: Vec<&str> = number.split('.').collect();
+    if !number.is_empty()
+        && components
+            .iter()
+            .all(|component| !component.is_empty() && component.bytes().all(|c| c.is_ascii_digit()))
+    {
+        return components.len();
+    }
+    1
+}
+
+fn is_number(word: &str) -> bool {
+    let word = word.trim_right_matches(|c: char| c.is_ascii_punctuation());
+    !word.is_empty()
+        && (word.bytes().all(|c| c.is_ascii_digit())
+            || word.chars().all(|c| "ivxlc".contains(c))
+            || NUMBER_WORDS.contains(&word))
+}
@@ -0,0 +1,143 @@
+use std::collections::BTreeMap;
+use std::time;
+
+use byteorder::{LittleEndian, ReadBytesExt};
+
+use errors::{ErrorKind, Result};
+use metadata::cue::Sheet;
+use metadata::picture::{cover, picture};
+use metadata::{base64, chapters_from_marks, Artwork, Metadata};
+
+fn malformed<S: Into<String>>(reason: S) -> ErrorKind {
+    ErrorKind::MalformedMedia("vorbis comment", reason.into())
+}
+
+/// Key and value pairs of a Vorbis comment block, as found in FLAC files
+/// and the comment packet of Ogg streams.
+pub fn comments(data: &[u8]) -> Result<Vec<(String, String)>> {
+    let mut cursor = data;
+    let vendor = cursor.read_u32::<LittleEndian>()? as usize;
+    if cursor.len() < vendor {
+        bail!(malformed("truncated vendor string"));
+    }
+    cursor = &cursor[vendor..];
+
+    let count = cursor.read_u32::<LittleEndian>()?;
+    let mut comments = vec![];
+    for _ in 0..count {
+        let length = cursor.read_u32::<LittleEndian>()? as usize;
+        if cursor.len() < length {
+            bail!(malformed("truncated comment"));
+        }
+        let comment = String::from_utf8_lossy(&cursor[..length]).into_owned();
+        cursor = &cursor[length..];
+
+        if let Some(split) = comment.find('=') {
+            comments.push((comment[..split].to_string(), comment[split + 1..].to_string()));
+        }
+    }
+    Ok(comments)
+}
+
+/// Fills in the standard tags, the cover, and chapters from `CHAPTERxxx`
+/// comments or an embedded CUE sheet.
+/// Chapters need the duration of the file, so it has to be known first.
+pub fn apply(metadata: &mut Metadata, comments: Vec<(String, String)>) {
+    let mut pictures: Vec<(u32, Artwork)> = vec![];
+    let mut starts = BTreeMap::new();
+    let mut names = BTreeMap::new();
+    let mut cuesheet = None;
+
+    for (key, value) in comments {
+        let key = key.to_uppercase();
+        if key.starts_with("CHAPTER") {
+            let rest = &key["CHAPTER".len()..];
+            let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+            let number: u32 = match rest[..digits].parse() {
+                Ok(number) => number,
+                Err(_) => continue,
+            };
+            match &rest[digits..] {
+                "" => {
+                    if let Some(start) = parse_time(&value) {
+                        starts.insert(number, start);
+                    }
+                }
+                "NAME" => {
+                    names.insert(number, value.trim().to_string());
+                }
+                _ => {}
+            }
+            continue;
+        }
+
+        match key.as_str() {
+            "TITLE" => set(&mut metadata.title, value),
+            "ARTIST" | "ALBUMARTIST" => set(&mut metadata.artist, value),
+            "ALBUM" => set(&mut metadata.album, value),
+            "COMPOSER" => set(&mut metadata.narrator, value),
+            "CUESHEET" => cuesheet = Some(value),
+            "METADATA_BLOCK_PICTURE" => {
+                pictures.extend(base64::decode(&value).and_then(|data| picture(&data)))
+            }
+            _ => {}
+        }
+    }
+
+    if metadata.artwork.is_none() {
+        metadata.artwork = cover(pictures);
+    }
+
+    if !starts.is_empty() {
+        let marks = starts
+            .into_iter()
+            .map(|(number, start)| {
+                let title = names
+                    .remove(&number)
+                    .unwrap_or_else(|| format!("Chapter {}", number));
+                (title, start)
+            })
+            .collect();
+        metadata.chapters = chapters_from_marks(marks, metadata.duration);
+    }
+
+    // Rippers embed the CUE sheet of a single file image as a comment.
+    if let Some(sheet) = cuesheet.and_then(|text| Sheet::parse(&text).ok()) {
+        if metadata.chapters.is_empty() && !sheet.files.is_empty() {
+            sheet.apply(metadata, 0);
+        }
+    }
+}
+
+/// The first of repeated tags wins, `ARTIST` may be given once per person.
+fn set(field: &mut String, value: String) {
+    if field.is_empty() {
+        *field = value.trim().to_string();
+    }
+}
+
+/// Chapter starts are written as `HH:MM:SS.sss`, hours and fractions being
+/// optional in the wild. Times too large for a `Duration` are rejected.
+fn parse_time(text: &str) -> Option<time::Duration> {
+    let text = text.trim();
+    let (clock, fraction) = match text.find('.') {
+        Some(dot) => (&text[..dot], &text[dot + 1..]),
+        None => (text, ""),
+    };
+    if !fraction.bytes().all(|digit| digit.is_ascii_digit()) {
+        return None;
+    }
+
+    let mut seconds: u64 = 0;
+    for field in clock.split(':') {
+        seconds = seconds
+            .checked_mul(60)?
+            .checked_add(field.parse::<u64>().ok()?)?;
+    }
+    let nanos = fraction
+        .bytes()
+        .chain(b"000000000".iter().cloned())
+        .take(9)
+        .fold(0u32, |nanos, digit| nanos * 10 + (digit - b'0') as u32);
+    Some(time::Duration::new(seconds, nanos))
+}
@@ -0,0 +1,620 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time;
+
+use dbus::arg::{RefArg, Variant};
+use dbus::stdintf::org_freedesktop_dbus::PropertiesPropertiesChanged;
+use dbus::tree::{Access, Factory, MethodErr};
+use dbus::{BusType, Connection, Message, NameFlag, Path, SignalArgs};
+
+use errors::Result;
+use player::{Event, Metadata, State, AUDIO_MIME_TYPES};
+use sleep::{from_seconds, seconds};
+use store::cache_dir;
+use uri;
+
+const BUS_NAME: &str = "org.mpris.MediaPlayer2.librebooks";
+const OBJECT_PATH: &str = "/org/mpris/MediaPlayer2";
+const ROOT_INTERFACE: &str = "org.mpris.MediaPlayer2";
+const PLAYER_INTERFACE: &str = "org.mpris.MediaPlayer2.Player";
+const TRACK_ID: &str = "/com/verestiuc/librebooks/CurrentBook";
+
+const POLL_MILLIS: u32 = 100;
+
+/// Position jumps this far from where playback should be by now count as
+/// seeks.
+const SEEK_THRESHOLD_MICROS: i64 = 2_000_000;
+
+type Properties = HashMap<String, Variant<Box<RefArg>>>;
+
+/// Requests coming from desktop clients, for the front-end to carry out on
+/// its player.
+#[derive(Debug, Clone)]
+pub enum Command {
+    Raise,
+    Quit,
+    Play,
+    Pause,
+    PlayPause,
+    Stop,
+    Next,
+    Previous,
+    SeekForward(time::Duration),
+    SeekBackward(time::Duration),
+    SetPosition(time::Duration),
+    SetRate(f64),
+    OpenUri(String),
+}
+
+pub enum Bus {
+    Session,
+    /// A bus by address, e.g. a private `dbus-daemon` started for tests.
+    Address(String),
+}
+
+enum Change {
+    Metadata,
+    PlaybackStatus,
+    Rate,
+    Seeked,
+}
+
+struct Status {
+    state: State,
+    metadata: Option<Metadata>,
+    art: Option<String>,
+    position: time::Duration,
+    /// When `position` was last known, playback has moved on since at `rate`
+    /// while playing.
+    updated: time::Instant,
+    rate: f64,
+}
+
+/// Exposes a player on D-Bus under `org.mpris.MediaPlayer2.librebooks`.
+/// Method calls are served on a thread of their own and forwarded as
+/// `Command`s, while the front-end feeds player events back via `handle`.
+pub struct Mpris {
+    status: Arc<Mutex<Status>>,
+    changes: mpsc::Sender<Change>,
+}
+
+impl Mpris {
+    pub fn spawn(bus: Bus, commands: mpsc::Sender<Command>) -> Result<Mpris> {
+        let status = Arc::new(Mutex::new(Status {
+            state: State::Stopped,
+            metadata: None,
+            art: None,
+            position: time::Duration::from_secs(0),
+            updated: time::Instant::now(),
+            rate: 1.0,
+        }));
+        let (changes, pending) = mpsc::channel();
+        let (ready, started) = mpsc::channel();
+
+        {
+            let status = status.clone();
+            thread::spawn(move || {
+                let connection = match serve(bus, status.clone(), commands) {
+                    Ok(connection) => {
+                        ready.send(Ok(())).is_ok();
+                        connection
+                    }
+                    Err(err) => {
+                        ready.send(Err(err)).is_ok();
+                        return;
+                    }
+                };
+
+                loop {
+                    for _ in connection.incoming(POLL_MILLIS) {}
+
+                    loop {
+                        match pending.try_recv() {
+                            Ok(change) => {
+                                connection.send(signal(&status, change)).is_ok();
+                            }
+                            Err(mpsc::TryRecvError::Empty) => break,
+                            Err(mpsc::TryRecvError::Disconnected) => return,
+                        }
+                    }
+                }
+            });
+        }
+
+        match started.recv() {
+            Ok(result) => result.map(|_| Mpris { status, changes }),
+            Err(_) => bail!("the MPRIS thread exited before connecting"),
+        }
+    }
+
+    /// Mirrors a player event on the bus.
+    pub fn handle(&self, event: &Event) {
+        let mut status = self.status.lock().unwrap();
+
+        let change = match *event {
+            Event::MetadataChanged(ref metadata) => {
+                status.art = store_art(metadata);
+                status.metadata = Some(metadata.clone());
+                status.position = time::Duration::from_secs(0);
+                status.updated = time::Instant::now();
+                Change::Metadata
+            }
+            Event::StateChanged(state) => {
+                status.position = expected_position(&status);
+                status.updated = time::Instant::now();
+                status.state = state;
+                Change::PlaybackStatus
+            }
+            Event::RateChanged(rate) => {
+                status.position = expected_position(&status);
+                status.updated = time::Instant::now();
+                status.rate = rate;
+                Change::Rate
+            }
+            Event::DurationChanged(duration) => {
+                match status.metadata {
+                    Some(ref mut metadata) => metadata.duration = duration,
+                    None => return,
+                }
+                Change::Metadata
+            }
+            Event::Progress(position) => {
+                let drift = micros(position) - micros(expected_position(&status));
+                status.position = position;
+                status.updated = time::Instant::now();
+                if drift.abs() < SEEK_THRESHOLD_MICROS {
+                    return;
+                }
+                Change::Seeked
+            }
+            _ => return,
+        };
+
+        self.changes.send(change).is_ok();
+    }
+}
+
+/// Where playback should be by now if nobody seeked.
+fn expected_position(status: &Status) -> time::Duration {
+    if status.state != State::Playing {
+        return status.position;
+    }
+    status.position + from_seconds(seconds(status.updated.elapsed()) * status.rate)
+}
+
+fn serve(
+    bus: Bus,
+    status: Arc<Mutex<Status>>,
+    commands: mpsc::Sender<Command>,
+) -> Result<Connection> {
+    let connection = match bus {
+        Bus::Session => Connection::get_private(BusType::Session)?,
+        Bus::Address(address) => {
+            let connection = Connection::open_private(&address)?;
+            connection.register()?;
+            connection
+        }
+    };
+    connection.register_name(BUS_NAME, NameFlag::ReplaceExisting as u32)?;
+
+    let f = Factory::new_fn::<()>();
+
+    macro_rules! command {
+        ($name:expr, $command:expr) => {{
+            let commands = commands.clone();
+            f.method($name, (), move |m| {
+                commands.send($command).is_ok();
+                Ok(vec![m.msg.method_return()])
+            })
+        }};
+    }
+
+    macro_rules! constant {
+        ($name:expr, $type:ty, $value:expr) => {
+            f.property::<$type, _>($name, ())
+                .on_get(|i, _| {
+                    i.append($value);
+                    Ok(())
+                })
+        };
+    }
+
+    macro_rules! status {
+        ($name:expr, $type:ty, |$status:ident| $value:expr) => {{
+            let status = status.clone();
+            f.property::<$type, _>($name, ()).on_get(move |i, _| {
+                let $status = status.lock().unwrap();
+                i.append($value);
+                Ok(())
+            })
+        }};
+    }
+
+    let root = f
+        .interface(ROOT_INTERFACE, ())
+        .add_m(command!("Raise", Command::Raise))
+        .add_m(command!("Quit", Command::Quit))
+        .add_p(constant!("CanQuit", bool, true))
+        .add_p(constant!("CanRaise", bool, true))
+        .add_p(constant!("HasTrackList", bool, false))
+        .add_p(constant!("Identity", &str, "Librebooks"))
+        .add_p(constant!("DesktopEntry", &str, "librebooks"))
+        .add_p(constant!("SupportedUriSchemes", Vec<&str>, vec!["file"]))
+        .add_p(constant!("SupportedMimeTypes", Vec<&str>, AUDIO_MIME_TYPES.to_vec()));
+
+    let seek = {
+        let commands = commands.clone();
+        f.method("Seek", (), move |m| {
+            let offset: i64 = m.msg.read1()?;
+            // i64::MIN has no positive counterpart, it seeks as far as MAX.
+            let delta = duration(offset.checked_abs().unwrap_or(i64::max_value()));
+            commands
+                .send(if offset < 0 {
+                    Command::SeekBackward(delta)
+                } else {
+                    Command::SeekForward(delta)
+                })
+                .is_ok();
+            Ok(vec![m.msg.method_return()])
+        }).inarg::<i64, _>("Offset")
+    };
+
+    let set_position = {
+        let status = status.clone();
+        let commands = commands.clone();
+        f.method("SetPosition", (), move |m| {
+            let (track, position): (Path, i64) = m.msg.read2()?;
+            // Stale track ids must be ignored, as must positions outside
+            // the book.
+            let length = status
+                .lock()
+                .unwrap()
+                .metadata
+                .as_ref()
+                .map(|metadata| metadata.duration);
+            let within = match length {
+                Some(length) => position >= 0 && duration(position) <= length,
+                None => false,
+            };
+            if &*track == TRACK_ID && within {
+                commands
+                    .send(Command::SetPosition(duration(position)))
+                    .is_ok();
+            }
+            Ok(vec![m.msg.method_return()])
+        }).inarg::<Path, _>("TrackId")
+            .inarg::<i64, _>("Position")
+    };
+
+    let open_uri = {
+        let commands = commands.clone();
+        f.method("OpenUri", (), move |m| {
+            let uri: String = m.msg.read1()?;
+            commands.send(Command::OpenUri(uri)).is_ok();
+            Ok(vec![m.msg.method_return()])
+        }).inarg::<&str, _>("Uri")
+    };
+
+    let rate = {
+        let status = status.clone();
+        let commands = commands.clone();
+        f.property::<f64, _>("Rate", ())
+            .access(Access::ReadWrite)
+            .on_get(move |i, _| {
+                i.append(status.lock().unwrap().rate);
+                Ok(())
+            })
+            .on_set(move |i, _| {
+                let rate: f64 = i.read()?;
+                // The spec has players treat a rate of zero as a pause.
+                if rate == 0.0 {
+                    commands.send(Command::Pause).is_ok();
+                    return Ok(());
+                }
+                if !(rate > 0.0) {
+                    return Err(MethodErr::invalid_arg(&rate));
+                }
+                commands.send(Command::SetRate(rate)).is_ok();
+                Ok(())
+            })
+    };
+
+    let player = f
+        .interface(PLAYER_INTERFACE, ())
+        .add_m(command!("Next", Command::Next))
+        .add_m(command!("Previous", Command::Previous))
+        .add_m(command!("Pause", Command::Pause))
+        .add_m(command!("PlayPause", Command::PlayPause))
+        .add_m(command!("Stop", Command::Stop))
+        .add_m(command!("Play", Command::Play))
+        .add_m(seek)
+        .add_m(set_position)
+        .add_m(open_uri)
+        .add_s(f.signal("Seeked", ()).sarg::<i64, _>("Position"))
+        .add_p(rate)
+        .add_p(status!("PlaybackStatus", &str, |status| playback_status(
+            status.state
+        )))
+        .add_p(status!("Metadata", Properties, |status| metadata_map(&status)))
+        .add_p(status!("Position", i64, |status| micros(status.position)))
+        .add_p(constant!("Volume", f64, 1.0))
+        .add_p(constant!("MinimumRate", f64, ::player::MIN_RATE))
+        .add_p(constant!("MaximumRate", f64, ::player::MAX_RATE))
+        .add_p(constant!("CanGoNext", bool, true))
+        .add_p(constant!("CanGoPrevious", bool, true))
+        .add_p(constant!("CanPlay", bool, true))
+        .add_p(constant!("CanPause", bool, true))
+        .add_p(constant!("CanSeek", bool, true))
+        .add_p(constant!("CanControl", bool, true));
+
+    let tree = f.tree(()).add(
+        f.object_path(OBJECT_PATH, ())
+            .introspectable()
+            .add(root)
+            .add(player),
+    );
+    tree.set_registered(&connection, true)?;
+    connection.add_handler(tree);
+
+    Ok(connection)
+}
+
+fn signal(status: &Arc<Mutex<Status>>, change: Change) -> Message {
+    let status = status.lock().unwrap();
+    let (name, value) = match change {
+        Change::Metadata => (
+            "Metadata",
+            Box::new(metadata_map(&status)) as Box<RefArg>,
+        ),
+        Change::PlaybackStatus => (
+            "PlaybackStatus",
+            Box::new(playback_status(status.state).to_string()) as Box<RefArg>,
+        ),
+        Change::Rate => ("Rate", Box::new(status.rate) as Box<RefArg>),
+        Change::Seeked => {
+            return Message::signal(
+                &OBJECT_PATH.into(),
+                &PLAYER_INTERFACE.into(),
+                &"Seeked".into(),
+            ).append1(micros(status.position));
+        }
+    };
+
+    let mut changed = Properties::new();
+    changed.insert(name.to_string(), Variant(value));
+    PropertiesPropertiesChanged {
+        interface_name: PLAYER_INTERFACE.to_string(),
+        changed_properties: changed,
+        invalidated_properties: vec![],
+    }.to_emit_message(&OBJECT_PATH.into())
+}
+
+fn playback_status(state: State) -> &'static str {
+    match state {
+        State::Playing => "Playing",
+        State::Paused | State::Buffering => "Paused",
+        _ => "Stopped",
+    }
+}
+
+fn metadata_map(status: &Status) -> Properties {
+    let mut map = Properties::new();
+    let metadata = match status.metadata {
+        Some(ref metadata) => metadata,
+        None => return map,
+    };
+
+    map.insert(
+        "mpris:trackid".to_string(),
+        Variant(Box::new(Path::from(TRACK_ID)) as Box<RefArg>),
+    );
+    map.insert(
+        "mpris:length".to_string(),
+        Variant(Box::new(micros(metadata.duration)) as Box<RefArg>),
+    );
+    map.insert(
+        "xesam:title".to_string(),
+        Variant(Box::new(metadata.title.clone()) as Box<RefArg>),
+    );
+    map.insert(
+        "xesam:album".to_string(),
+        Variant(Box::new(metadata.album.clone()) as Box<RefArg>),
+    );
+    if !metadata.artist.is_empty() {
+        map.insert(
+            "xesam:artist".to_string(),
+            Variant(Box::new(vec![metadata.artist.clone()]) as Box<RefArg>),
+        );
+    }
+    if !metadata.narrator.is_empty() {
+        map.insert(
+            "xesam:composer".to_string(),
+            Variant(Box::new(vec![metadata.narrator.clone()]) as Box<RefArg>),
+        );
+    }
+    if let Some(ref art) = status.art {
+        map.insert(
+            "mpris:artUrl".to_string(),
+            Variant(Box::new(art.clone()) as Box<RefArg>),
+        );
+    }
+    map
+}
+
+/// Desktop shells want cover art as a URL, so the cover is written out to
+/// the cache dir. Shells cache images by URL, so every cover gets a name of
+/// its own and the one before is removed.
+fn store_art(metadata: &Metadata) -> Option<String> {
+    let artwork = metadata.artwork.as_ref()?;
+
+    let mut hasher = DefaultHasher::new();
+    artwork.data.hash(&mut hasher);
+    let extension = if artwork.mime == "image/png" { "png" } else { "jpg" };
+    let name = format!("{:016x}.{}", hasher.finish(), extension);
+    let dir = cache_dir().join("mpris-art");
+    let path = dir.join(&name);
+
+    fs::create_dir_all(&dir).ok()?;
+    if let Ok(entries) = fs::read_dir(&dir) {
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            if entry.file_name().to_str() != Some(name.as_str()) {
+                fs::remove_file(entry.path()).is_ok();
+            }
+        }
+    }
+    if !path.exists() {
+        fs::write(&path, &artwork.data).ok()?;
+    }
+    uri::from_path(&path).ok()
+}
+
+fn micros(duration: time::Duration) -> i64 {
+    duration.as_secs() as i64 * 1_000_000 + duration.subsec_micros() as i64
+}
+
+fn duration(micros: i64) -> time::Duration {
+    time::Duration::from_micros(micros as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader};
+    use std::process;
+    use std::sync::mpsc;
+    use std::time;
+
+    use std::path;
+
+    use dbus::arg::Variant;
+    use dbus::{Connection, Message, Path};
+
+    use super::{
+        duration, Bus, Command, Mpris, BUS_NAME, OBJECT_PATH, PLAYER_INTERFACE, TRACK_ID,
+    };
+    use player::{Event, Metadata};
+
+    const TIMEOUT_MILLIS: i32 = 2000;
+    const PROPERTIES_INTERFACE: &str = "org.freedesktop.DBus.Properties";
+
+    /// A `dbus-daemon` of our own, so tests neither need nor disturb the
+    /// session bus.
+    struct Daemon {
+        child: process::Child,
+        address: String,
+    }
+
+    impl Daemon {
+        fn start() -> Option<Daemon> {
+            let mut child = process::Command::new("dbus-daemon")
+                .args(&["--session", "--nofork", "--print-address"])
+                .stdout(process::Stdio::piped())
+                .spawn()
+                .ok()?;
+            let mut address = String::new();
+            BufReader::new(child.stdout.take()?)
+                .read_line(&mut address)
+                .ok()?;
+            Some(Daemon {
+                child,
+                address: address.trim().to_string(),
+            })
+        }
+    }
+
+    impl Drop for Daemon {
+        fn drop(&mut self) {
+            self.child.kill().is_ok();
+            self.child.wait().is_ok();
+        }
+    }
+
+    fn call(client: &Connection, interface: &str, method: &str) -> Message {
+        Message::new_method_call(BUS_NAME, OBJECT_PATH, interface, method).unwrap()
+    }
+
+    fn set_rate(client: &Connection, rate: f64) -> bool {
+        let message = call(client, PROPERTIES_INTERFACE, "Set").append3(
+            PLAYER_INTERFACE,
+            "Rate",
+            Variant(rate),
+        );
+        client
+            .send_with_reply_and_block(message, TIMEOUT_MILLIS)
+            .is_ok()
+    }
+
+    fn set_position(client: &Connection, micros: i64) {
+        let message = call(client, PLAYER_INTERFACE, "SetPosition")
+            .append2(Path::from(TRACK_ID), micros);
+        client
+            .send_with_reply_and_block(message, TIMEOUT_MILLIS)
+            .unwrap();
+    }
+
+    fn next(commands: &mpsc::Receiver<Command>) -> Command {
+        commands
+            .recv_timeout(time::Duration::from_millis(TIMEOUT_MILLIS as u64))
+            .expect("a command")
+    }
+
+    #[test]
+    fn serves_a_private_bus() {
+        let daemon = match Daemon::start() {
+            Some(daemon) => daemon,
+            None => {
+                eprintln!("dbus-daemon is not available, skipping");
+                return;
+            }
+        };
+        let (sender, commands) = mpsc::channel();
+        let mpris = Mpris::spawn(Bus::Address(daemon.address.clone()), sender).unwrap();
+
+        let client = Connection::open_private(&daemon.address).unwrap();
+        client.register().unwrap();
+
+        let status = call(&client, PROPERTIES_INTERFACE, "Get")
+            .append2(PLAYER_INTERFACE, "PlaybackStatus");
+        let reply = client
+            .send_with_reply_and_block(status, TIMEOUT_MILLIS)
+            .unwrap();
+        let status: Variant<String> = reply.read1().unwrap();
+        assert_eq!(status.0, "Stopped");
+
+        let seek = call(&client, PLAYER_INTERFACE, "Seek").append1(i64::min_value());
+        client
+            .send_with_reply_and_block(seek, TIMEOUT_MILLIS)
+            .unwrap();
+        match next(&commands) {
+            Command::SeekBackward(delta) => assert_eq!(delta, duration(i64::max_value())),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        assert!(set_rate(&client, 1.5));
+        match next(&commands) {
+            Command::SetRate(rate) => assert_eq!(rate, 1.5),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        assert!(set_rate(&client, 0.0));
+        match next(&commands) {
+            Command::Pause => {}
+            other => panic!("unexpected {:?}", other),
+        }
+
+        assert!(!set_rate(&client, -1.0));
+
+        let mut metadata = Metadata::empty(&path::PathBuf::from("book.m4b"));
+        metadata.duration = time::Duration::from_secs(60);
+        mpris.handle(&Event::MetadataChanged(metadata));
+        set_position(&client, 90_000_000);
+        set_position(&client, 30_000_000);
+        match next(&commands) {
+            Command::SetPosition(position) => assert_eq!(position, time::Duration::from_secs(30)),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,710 @@
+use glib::prelude::*;
+use glib::Cast;
+use gst;
+use gst_player;
+pub use gst_player::PlayerState as State;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::path;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time;
+
+pub use book::{Book, AUDIO_EXTENSIONS, AUDIO_MIME_TYPES};
+pub use gst::ClockTime;
+pub use metadata::{Artwork, Chapter, Metadata};
+pub use sleep::{Mode as SleepMode, SleepTimer};
+use settings::{PlaybackSettings, Resume, Settings};
+use sleep::{from_seconds, seconds};
+pub use store::Record;
+use store::Store;
+use uri;
+use CommandChannel;
+
+const SAVE_INTERVAL_SECS: u64 = 10;
+
+pub const MIN_RATE: f64 = 0.5;
+pub const MAX_RATE: f64 = 3.0;
+
+/// How far smart rewind goes back after a pause of at least the given
+/// length, in seconds.
+const REWIND_STEPS: &[(u64, u64)] = &[(10, 3), (60, 10), (600, 20), (3600, 30)];
+
+/// Where the player actor sends the answer to a query.
+pub type Reply<T> = mpsc::Sender<T>;
+
+/// Messages understood by the player actor, see `Player::actor`.
+#[derive(Debug)]
+pub enum Command {
+    Open(path::PathBuf),
+    /// The files of one book, in playback order.
+    OpenFiles(Vec<path::PathBuf>),
+    /// A `file://` URI or a plain path, as handed over by a desktop shell.
+    OpenUri(String),
+    OpenBook(Book),
+    Play,
+    Pause,
+    Seek(time::Duration),
+    SetRate(f64),
+    SetVolume(f64),
+    Stop,
+    SetSleepTimer(SleepTimer),
+    ExtendSleepTimer(time::Duration),
+    CancelSleepTimer,
+    ApplySettings(Settings),
+    Position(Reply<time::Duration>),
+    Rate(Reply<f64>),
+    BookId(Reply<Option<String>>),
+    /// What was saved about every book, keyed by `Book::id`.
+    Records(Reply<HashMap<String, Record>>),
+    /// Saves the position and ends the actor.
+    Quit,
+}
+
+/// Sends a query to the player actor and waits for the answer. `None` when
+/// the actor is gone.
+pub fn ask<T, F>(commands: &mpsc::Sender<Command>, query: F) -> Option<T>
+where
+    F: FnOnce(Reply<T>) -> Command,
+{
+    let (reply, answer) = mpsc::channel();
+    commands.send(query(reply)).ok()?;
+    answer.recv().ok()
+}
+
+/// Why a book could not be opened or played, along with the file at fault.
+#[derive(Debug, Clone)]
+pub enum PlaybackError {
+    /// The file or folder could not be read as a book.
+    Open { path: path::PathBuf, reason: String },
+    /// GStreamer failed while playing a part of the book.
+    Stream {
+        path: Option<path::PathBuf>,
+        reason: String,
+    },
+}
+
+impl PlaybackError {
+    pub fn path(&self) -> Option<&path::Path> {
+        match *self {
+            PlaybackError::Open { ref path, .. } => Some(path),
+            PlaybackError::Stream { ref path, .. } => path.as_ref().map(|path| path.as_path()),
+        }
+    }
+
+    pub fn reason(&self) -> &str {
+        match *self {
+            PlaybackError::Open { ref reason, .. } | PlaybackError::Stream { ref reason, .. } => {
+                reason
+            }
+        }
+    }
+}
+
+impl fmt::Display for PlaybackError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PlaybackError::Open { ref path, ref reason } => {
+                write!(f, "could not open {}: {}", path.display(), reason)
+            }
+            PlaybackError::Stream {
+                path: Some(ref path),
+                ref reason,
+            } => write!(f, "could not play {}: {}", path.display(), reason),
+            PlaybackError::Stream {
+                path: None,
+                ref reason,
+            } => write!(f, "playback failed: {}", reason),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Event {
+    MetadataChanged(Metadata),
+    StateChanged(State),
+    Progress(time::Duration),
+    RateChanged(f64),
+    SleepCountdown(Option<time::Duration>),
+    Error(PlaybackError),
+    /// The last part of the book played to its end.
+    EndOfStream,
+    /// Percentage of the stream buffered, 100 once playback can go on.
+    BufferingChanged(i32),
+    /// Length of the book, corrected with what GStreamer measured for the
+    /// parts played so far.
+    DurationChanged(time::Duration),
+    /// Something went wrong that playback carries on despite, such as saved
+    /// positions that could not be read.
+    Warning(String),
+}
+
+struct Playback {
+    book: Option<Book>,
+    part: usize,
+    state: State,
+    position: time::Duration,
+    rate: f64,
+    store: Store,
+    saved: time::Instant,
+    sleep: Option<SleepTimer>,
+    volume: f64,
+    paused: Option<time::Instant>,
+    settings: PlaybackSettings,
+}
+
+impl Playback {
+    fn offset(&self) -> time::Duration {
+        self.book
+            .as_ref()
+            .map(|book| book.parts[self.part].offset)
+            .unwrap_or(time::Duration::from_secs(0))
+    }
+
+    /// Points the pipeline at another part of the book, resuming
+    /// playback if it was running.
+    fn switch_part(&mut self, player: &gst_player::Player, part: usize) {
+        let uri = match self.book {
+            Some(ref book) => match uri::from_path(&book.parts[part].metadata.path) {
+                Ok(uri) => uri,
+                Err(_) => return,
+            },
+            None => return,
+        };
+        self.part = part;
+        player.set_uri(&uri);
+        if self.state == State::Playing {
+            player.play();
+        }
+    }
+
+    /// Seeks on the book timeline. The position is taken over right away,
+    /// position updates only come in while playing.
+    fn seek(&mut self, player: &gst_player::Player, position: time::Duration) {
+        let (part, within) = match self.book.as_ref().and_then(|book| book.part_at(position)) {
+            Some(target) => target,
+            None => return,
+        };
+
+        if part != self.part {
+            self.switch_part(player, part);
+        }
+        player.seek(ClockTime::from_nseconds(within.as_nanos() as u64));
+        self.remember(position);
+    }
+
+    fn remember(&mut self, position: time::Duration) {
+        self.position = position;
+
+        let (id, chapter) = match self.book {
+            Some(ref book) => (book.id.clone(), book.metadata.chapter_at(position)),
+            None => return,
+        };
+        self.store.set(
+            &id,
+            Record {
+                position,
+                chapter,
+                rate: self.rate,
+                updated: time::SystemTime::now(),
+            },
+        );
+    }
+
+    fn save(&mut self) {
+        if self.book.is_some() && self.store.save().is_ok() {
+            self.saved = time::Instant::now();
+        }
+    }
+
+    /// End of the chapter playing, or of the book when no chapter is.
+    fn chapter_end(&self) -> Option<time::Duration> {
+        let metadata = &self.book.as_ref()?.metadata;
+        match metadata.chapter_at(self.position) {
+            Some(chapter) => Some(metadata.chapters[chapter].end),
+            None => Some(metadata.duration),
+        }
+    }
+
+    fn tick_sleep(&mut self, player: &gst_player::Player) -> Option<Event> {
+        let (position, chapter_end, rate) = (self.position, self.chapter_end()?, self.rate);
+        let (remaining, volume) = match self.sleep {
+            Some(ref mut timer) => (timer.tick(position, chapter_end, rate), timer.volume()),
+            None => return None,
+        };
+
+        if remaining == time::Duration::from_secs(0) {
+            self.sleep = None;
+            player.pause();
+            player.set_volume(self.volume);
+            return Some(Event::SleepCountdown(None));
+        }
+
+        if volume < 1.0 {
+            player.set_volume(self.volume * volume);
+        }
+        Some(Event::SleepCountdown(Some(remaining)))
+    }
+
+    /// Steps back when resuming after a pause, so the listener catches
+    /// up with where they were.
+    fn rewind(&mut self, player: &gst_player::Player) {
+        let paused = match self.paused.take() {
+            Some(paused) if self.settings.smart_rewind => paused.elapsed().as_secs(),
+            _ => return,
+        };
+        let rewind = match REWIND_STEPS.iter().rev().find(|&&(after, _)| paused >= after) {
+            Some(&(_, rewind)) => time::Duration::from_secs(rewind),
+            None => return,
+        };
+
+        let position = self
+            .position
+            .checked_sub(rewind)
+            .unwrap_or(time::Duration::from_secs(0));
+        self.seek(player, position);
+    }
+
+    /// Gives a sleep timer that resets on interaction a fresh start and
+    /// undoes any fade-out in progress.
+    fn interact(&mut self, player: &gst_player::Player) {
+        if let Some(ref mut timer) = self.sleep {
+            timer.interact();
+            player.set_volume(self.volume);
+        }
+    }
+}
+
+pub struct Player {
+    player: gst_player::Player,
+    events: mpsc::Sender<Event>,
+    playback: Arc<Mutex<Playback>>,
+}
+
+impl CommandChannel for Player {
+    type Command = Command;
+    type Event = Event;
+}
+
+impl Player {
+    /// Runs a player on a thread of its own, driven by commands. Signals are
+    /// still delivered through the default GLib main context, so a main loop
+    /// has to run somewhere.
+    pub fn actor() -> (mpsc::Sender<Command>, mpsc::Receiver<Event>) {
+        Player::spawn(|commands, events| {
+            let player = Player::new(events);
+            for command in commands.iter() {
+                if !player.handle(command) {
+                    break;
+                }
+            }
+            player.close();
+        })
+    }
+
+    /// Carries out a command, returns false once the actor should stop.
+    fn handle(&self, command: Command) -> bool {
+        match command {
+            Command::Open(path) => self.open(path),
+            Command::OpenFiles(files) => self.open_files(files),
+            Command::OpenUri(uri) => self.open_uri(&uri),
+            Command::OpenBook(book) => self.open_book(book),
+            Command::Play => self.play(),
+            Command::Pause => self.pause(),
+            Command::Seek(position) => self.seek(position),
+            Command::SetRate(rate) => self.set_rate(rate),
+            Command::SetVolume(volume) => self.set_volume(volume),
+            Command::Stop => self.stop(),
+            Command::SetSleepTimer(timer) => self.set_sleep_timer(timer),
+            Command::ExtendSleepTimer(by) => self.extend_sleep_timer(by),
+            Command::CancelSleepTimer => self.cancel_sleep_timer(),
+            Command::ApplySettings(settings) => self.apply_settings(&settings),
+            Command::Position(reply) => {
+                reply.send(self.position()).is_ok();
+            }
+            Command::Rate(reply) => {
+                reply.send(self.rate()).is_ok();
+            }
+            Command::BookId(reply) => {
+                reply.send(self.book_id()).is_ok();
+            }
+            Command::Records(reply) => {
+                reply.send(self.records()).is_ok();
+            }
+            Command::Quit => return false,
+        }
+        true
+    }
+
+    pub fn new(events: mpsc::Sender<Event>) -> Player {
+        let dispatcher = gst_player::PlayerGMainContextSignalDispatcher::new(None);
+        let player = gst_player::Player::new(
+            None,
+            Some(&dispatcher.upcast::<gst_player::PlayerSignalDispatcher>()),
+        );
+
+        // scaletempo keeps voices at their natural pitch when sped up.
+        if let Some(scaletempo) = gst::ElementFactory::make("scaletempo", None) {
+            player
+                .get_pipeline()
+                .set_property("audio-filter", &scaletempo)
+                .is_ok();
+        }
+
+        let playback = Arc::new(Mutex::new(Playback {
+            book: None,
+            part: 0,
+            state: State::Stopped,
+            position: time::Duration::from_secs(0),
+            rate: 1.0,
+            store: Store::open(),
+            saved: time::Instant::now(),
+            sleep: None,
+            volume: 1.0,
+            paused: None,
+            settings: Settings::open().playback,
+        }));
+
+        if let Some(problem) = playback.lock().unwrap().store.problem() {
+            events
+                .send(Event::Warning(problem.to_string()))
+                .expect("delivered");
+        }
+
+        player.connect_end_of_stream(clone!(events, playback => move |player| {
+            let mut playback = playback.lock().unwrap();
+            let next = playback.part + 1;
+            let parts = playback.book.as_ref().map(|book| book.parts.len()).unwrap_or(0);
+            if next < parts {
+                playback.switch_part(player, next);
+            } else {
+                player.stop();
+                events.send(Event::EndOfStream).expect("delivered");
+            }
+        }));
+
+        player.connect_error(clone!(events, playback => move |player, err| {
+            let playback = playback.lock().unwrap();
+            let path = playback
+                .book
+                .as_ref()
+                .map(|book| book.parts[playback.part].metadata.path.clone());
+            player.stop();
+            events
+                .send(Event::Error(PlaybackError::Stream {
+                    path,
+                    reason: err.to_string(),
+                }))
+                .expect("delivered");
+        }));
+
+        player.connect_buffering(clone!(events => move |_, percent| {
+            events.send(Event::BufferingChanged(percent)).expect("delivered");
+        }));
+
+        player.connect_duration_changed(clone!(events, playback => move |_, duration| {
+            let mut playback = playback.lock().unwrap();
+            let measured = match duration.nanoseconds() {
+                Some(nanoseconds) => time::Duration::from_nanos(nanoseconds),
+                None => return,
+            };
+            let part = playback.part;
+            let total = match playback.book {
+                Some(ref mut book) => {
+                    if !book.correct_duration(part, measured) {
+                        return;
+                    }
+                    book.metadata.duration
+                }
+                None => return,
+            };
+            events.send(Event::DurationChanged(total)).expect("delivered");
+        }));
+
+        player.connect_state_changed(clone!(events, playback => move |_, state| {
+            let mut playback = playback.lock().unwrap();
+            let previous = playback.state;
+            playback.state = state;
+            if state == State::Paused || state == State::Stopped {
+                playback.save();
+            }
+            // The pause is timed from when it starts, whatever caused it, and
+            // forgotten once playing again however that came about.
+            match state {
+                State::Paused if previous != State::Paused => {
+                    playback.paused = Some(time::Instant::now())
+                }
+                State::Playing => playback.paused = None,
+                _ => {}
+            }
+            if state != State::Playing {
+                if let Some(ref mut timer) = playback.sleep {
+                    timer.pause();
+                }
+            }
+            events.send(Event::StateChanged(state)).expect("delivered");
+        }));
+
+        player.connect_position_updated(clone!(events, playback => move |player, position| {
+            if let Some(nanoseconds) = position.nanoseconds() {
+                let mut playback = playback.lock().unwrap();
+                let position = playback.offset() + time::Duration::from_nanos(nanoseconds);
+                events.send(Event::Progress(position)).expect("delivered");
+
+                if playback.state == State::Playing {
+                    playback.remember(position);
+                    if playback.saved.elapsed().as_secs() >= SAVE_INTERVAL_SECS {
+                        playback.save();
+                    }
+                    if let Some(event) = playback.tick_sleep(player) {
+                        events.send(event).expect("delivered");
+                    }
+                }
+            }
+        }));
+
+        Player {
+            player,
+            events,
+            playback,
+        }
+    }
+
+    pub fn open(&self, path: path::PathBuf) {
+        match Book::open(&path) {
+            Ok(book) => self.open_book(book),
+            Err(err) => self
+                .events
+                .send(Event::Error(PlaybackError::Open {
+                    path,
+                    reason: err.to_string(),
+                }))
+                .expect("delivered"),
+        }
+    }
+
+    /// Reads a book from its files, on the actor rather than the caller's
+    /// thread as every file is probed.
+    pub fn open_files(&self, files: Vec<path::PathBuf>) {
+        // A failure is pinned on the file when there is just one, on their
+        // folder otherwise.
+        let path = match files.len() {
+            1 => files[0].clone(),
+            _ => files
+                .first()
+                .and_then(|file| file.parent())
+                .map(|folder| folder.to_path_buf())
+                .unwrap_or_default(),
+        };
+        match Book::from_files(files) {
+            Ok(book) => self.open_book(book),
+            Err(err) => self
+                .events
+                .send(Event::Error(PlaybackError::Open {
+                    path,
+                    reason: err.to_string(),
+                }))
+                .expect("delivered"),
+        }
+    }
+
+    pub fn open_uri(&self, input: &str) {
+        match uri::to_path(input) {
+            Ok(path) => self.open(path),
+            Err(err) => self
+                .events
+                .send(Event::Error(PlaybackError::Open {
+                    path: path::PathBuf::from(input),
+                    reason: err.to_string(),
+                }))
+                .expect("delivered"),
+        }
+    }
+
+    /// Opens a book and resumes it where it was left the last time, or
+    /// wherever the resume setting says.
+    pub fn open_book(&self, book: Book) {
+        let uri = match uri::from_path(&book.parts[0].metadata.path) {
+            Ok(uri) => uri,
+            Err(err) => {
+                self.events
+                    .send(Event::Error(PlaybackError::Open {
+                        path: book.parts[0].metadata.path.clone(),
+                        reason: err.to_string(),
+                    }))
+                    .expect("delivered");
+                return;
+            }
+        };
+
+        self.events
+            .send(Event::MetadataChanged(book.metadata.clone()))
+            .expect("delivered");
+
+        let mut playback = self.playback.lock().unwrap();
+        playback.save();
+
+        let (resume, rate) = match playback.store.get(&book.id) {
+            Some(record) => (
+                resume_at(&book, record.position, playback.settings.resume),
+                record.rate,
+            ),
+            None => (None, playback.settings.default_rate),
+        };
+
+        playback.book = Some(book);
+        playback.part = 0;
+        playback.paused = None;
+        playback.position = resume.unwrap_or(time::Duration::from_secs(0));
+        self.player.set_uri(&uri);
+        self.apply_rate(&mut playback, rate);
+
+        if let Some(position) = resume {
+            playback.seek(&self.player, position);
+        }
+    }
+
+    /// Changes the playback speed, keeping the pitch of the voice.
+    pub fn set_rate(&self, rate: f64) {
+        let mut playback = self.playback.lock().unwrap();
+        playback.interact(&self.player);
+        self.apply_rate(&mut playback, rate);
+    }
+
+    pub fn rate(&self) -> f64 {
+        self.playback.lock().unwrap().rate
+    }
+
+    fn apply_rate(&self, playback: &mut Playback, rate: f64) {
+        let rate = rate.max(MIN_RATE).min(MAX_RATE);
+        playback.rate = rate;
+        self.player.set_rate(rate);
+
+        let position = playback.position;
+        playback.remember(position);
+        self.events
+            .send(Event::RateChanged(rate))
+            .expect("delivered");
+    }
+
+    /// Seeks to a position on the book timeline, switching files when
+    /// the position lies in another part.
+    pub fn seek(&self, position: time::Duration) {
+        let mut playback = self.playback.lock().unwrap();
+        playback.interact(&self.player);
+        playback.seek(&self.player, position);
+    }
+    pub fn play(&self) {
+        let mut playback = self.playback.lock().unwrap();
+        playback.interact(&self.player);
+        playback.rewind(&self.player);
+        self.player.play();
+    }
+
+    pub fn pause(&self) {
+        self.playback.lock().unwrap().interact(&self.player);
+        self.player.pause();
+    }
+
+    pub fn stop(&self) {
+        self.playback.lock().unwrap().save();
+        self.player.stop();
+    }
+
+    /// Sets the listener's volume, a sleep timer fades out from there.
+    pub fn set_volume(&self, volume: f64) {
+        let mut playback = self.playback.lock().unwrap();
+        playback.volume = volume.max(0.0).min(1.0);
+        self.player.set_volume(playback.volume);
+    }
+
+    pub fn position(&self) -> time::Duration {
+        self.playback.lock().unwrap().position
+    }
+
+    /// Starts a sleep timer, replacing the running one if any.
+    pub fn set_sleep_timer(&self, timer: SleepTimer) {
+        let mut playback = self.playback.lock().unwrap();
+        if playback.sleep.is_none() {
+            playback.volume = self.player.get_volume();
+        } else {
+            self.player.set_volume(playback.volume);
+        }
+
+        let remaining = match timer.mode() {
+            SleepMode::After(duration) => Some(duration),
+            SleepMode::EndOfChapter => playback
+                .chapter_end()
+                .and_then(|end| end.checked_sub(playback.position))
+                .map(|left| from_seconds(seconds(left) / playback.rate)),
+        };
+        playback.sleep = Some(timer);
+        self.events
+            .send(Event::SleepCountdown(remaining))
+            .expect("delivered");
+    }
+
+    pub fn extend_sleep_timer(&self, by: time::Duration) {
+        let mut playback = self.playback.lock().unwrap();
+        let remaining = match playback.sleep {
+            Some(ref mut timer) => {
+                timer.extend(by);
+                timer.remaining()
+            }
+            None => return,
+        };
+        self.player.set_volume(playback.volume);
+        self.events
+            .send(Event::SleepCountdown(Some(remaining)))
+            .expect("delivered");
+    }
+
+    pub fn cancel_sleep_timer(&self) {
+        let mut playback = self.playback.lock().unwrap();
+        if playback.sleep.take().is_some() {
+            self.player.set_volume(playback.volume);
+            self.events
+                .send(Event::SleepCountdown(None))
+                .expect("delivered");
+        }
+    }
+
+    /// Takes up changed preferences, they apply from the next action on.
+    pub fn apply_settings(&self, settings: &Settings) {
+        self.playback.lock().unwrap().settings = settings.playback.clone();
+    }
+
+    /// What was saved about every book, e.g. to show progress in a library.
+    pub fn records(&self) -> HashMap<String, Record> {
+        self.playback.lock().unwrap().store.records().clone()
+    }
+
+    pub fn book_id(&self) -> Option<String> {
+        self.playback
+            .lock()
+            .unwrap()
+            .book
+            .as_ref()
+            .map(|book| book.id.clone())
+    }
+
+    /// Flushes the listening position to disk, meant for shutdown.
+    pub fn close(&self) {
+        self.playback.lock().unwrap().save();
+    }
+}
+
+fn resume_at(book: &Book, position: time::Duration, resume: Resume) -> Option<time::Duration> {
+    if position >= book.metadata.duration {
+        return None;
+    }
+    match resume {
+        Resume::Position => Some(position),
+        Resume::ChapterStart => book
+            .metadata
+            .chapter_at(position)
+            .map(|chapter| book.metadata.chapters[chapter].start)
+            .or(Some(position)),
+        Resume::Beginning => None,
+    }
+}
@@ -0,0 +1,251 @@
+use std::path;
+use std::time;
+
+use serde_json::{self, Map, Value};
+
+use errors::{ErrorKind, Result};
+use library::default_folders;
+use store::{config_dir, read_json, set_aside, write_json};
+
+/// Bumped whenever the layout of the settings file changes, with a step
+/// added to `migrate` that brings older files up to date.
+const VERSION: u64 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Theme {
+    System,
+    Light,
+    Dark,
+}
+
+/// Where a book picks up when it is opened again.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Resume {
+    Position,
+    ChapterStart,
+    Beginning,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PlaybackSettings {
+    pub skip_forward_secs: u64,
+    pub skip_backward_secs: u64,
+    /// Speed of books that were never played before.
+    pub default_rate: f64,
+    /// Rewind a little when resuming, more the longer playback was paused.
+    pub smart_rewind: bool,
+    pub resume: Resume,
+}
+
+impl Default for PlaybackSettings {
+    fn default() -> PlaybackSettings {
+        PlaybackSettings {
+            skip_forward_secs: 30,
+            skip_backward_secs: 10,
+            default_rate: 1.0,
+            smart_rewind: true,
+            resume: Resume::Position,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LibrarySettings {
+    #[serde(with = "::store::os_path::list")]
+    pub folders: Vec<path::PathBuf>,
+}
+
+impl Default for LibrarySettings {
+    fn default() -> LibrarySettings {
+        LibrarySettings {
+            folders: default_folders(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SleepSettings {
+    /// Durations offered in the sleep timer menu.
+    pub minutes: Vec<u64>,
+    pub extension_minutes: u64,
+    pub fade_secs: u64,
+    pub reset_on_interaction: bool,
+}
+
+impl Default for SleepSettings {
+    fn default() -> SleepSettings {
+        SleepSettings {
+            minutes: vec![15, 30, 45, 60],
+            extension_minutes: 5,
+            fade_secs: 10,
+            reset_on_interaction: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Appearance {
+    pub theme: Theme,
+}
+
+impl Default for Appearance {
+    fn default() -> Appearance {
+        Appearance {
+            theme: Theme::System,
+        }
+    }
+}
+
+/// User preferences, kept as JSON in the user config dir. Missing keys fall
+/// back to their defaults.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub version: u64,
+    pub playback: PlaybackSettings,
+    pub library: LibrarySettings,
+    pub sleep: SleepSettings,
+    pub appearance: Appearance,
+    #[serde(skip)]
+    path: path::PathBuf,
+    /// Cleared when the file on disk must not be written over.
+    #[serde(skip)]
+    writable: bool,
+    /// What went wrong reading the file, for the listener to see.
+    #[serde(skip)]
+    problem: Option<String>,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            version: VERSION,
+            playback: PlaybackSettings::default(),
+            library: LibrarySettings::default(),
+            sleep: SleepSettings::default(),
+            appearance: Appearance::default(),
+            path: config_dir().join("settings.json"),
+            writable: true,
+            problem: None,
+        }
+    }
+}
+
+impl Settings {
+    pub fn open() -> Settings {
+        let path = config_dir().join("settings.json");
+        match Settings::load(&path) {
+            Ok(settings) => settings,
+            Err(err) => {
+                // Settings of a newer version are kept for that version,
+                // anything else unreadable is set aside.
+                let (writable, problem) = match *err.kind() {
+                    ErrorKind::NewerSettings(..) => (false, err.to_string()),
+                    _ => match set_aside(&path) {
+                        Ok(aside) => (
+                            true,
+                            ErrorKind::SetAside(path.clone(), aside, err.to_string()).to_string(),
+                        ),
+                        Err(_) => (
+                            false,
+                            ErrorKind::Unreadable(path.clone(), err.to_string()).to_string(),
+                        ),
+                    },
+                };
+                Settings {
+                    path,
+                    writable,
+                    problem: Some(problem),
+                    ..Default::default()
+                }
+            }
+        }
+    }
+
+    /// Reads a settings file of any known version. Older files are upgraded
+    /// and written back, or upgraded again next time when that fails.
+    pub fn load(path: &path::Path) -> Result<Settings> {
+        let value: Value = match read_json(path)? {
+            Some(value) => value,
+            None => {
+                return Ok(Settings {
+                    path: path.to_path_buf(),
+                    ..Default::default()
+                })
+            }
+        };
+
+        let (value, migrated) = migrate(value)?;
+        let mut settings: Settings = serde_json::from_value(value)?;
+        settings.path = path.to_path_buf();
+        if migrated {
+            settings.save().is_ok();
+        }
+        Ok(settings)
+    }
+
+    pub fn problem(&self) -> Option<&str> {
+        self.problem.as_ref().map(|problem| problem.as_str())
+    }
+
+    pub fn skip_forward(&self) -> time::Duration {
+        time::Duration::from_secs(self.playback.skip_forward_secs)
+    }
+
+    pub fn skip_backward(&self) -> time::Duration {
+        time::Duration::from_secs(self.playback.skip_backward_secs)
+    }
+
+    pub fn save(&self) -> Result<()> {
+        if !self.writable {
+            bail!(ErrorKind::Protected(self.path.clone()));
+        }
+        write_json(&self.path, self)
+    }
+}
+
+/// Upgrades settings one version at a time. Files written before versioning
+/// was introduced count as version 1.
+fn migrate(mut value: Value) -> Result<(Value, bool)> {
+    let found = value.get("version").and_then(Value::as_u64).unwrap_or(1);
+    if found > VERSION {
+        bail!(ErrorKind::NewerSettings(found, VERSION));
+    }
+
+    let mut version = found;
+    while version < VERSION {
+        value = match version {
+            1 => flat_to_sections(value),
+            _ => value,
+        };
+        version += 1;
+    }
+
+    if let Value::Object(ref mut map) = value {
+        map.insert("version".to_string(), Value::from(VERSION));
+    }
+    Ok((value, found != VERSION))
+}
+
+/// Version 1 only knew about playback and kept it at the top level.
+fn flat_to_sections(value: Value) -> Value {
+    let mut flat = match value {
+        Value::Object(map) => map,
+        other => return other,
+    };
+
+    let mut playback = Map::new();
+    for key in &["skip_forward_secs", "skip_backward_secs", "smart_rewind"] {
+        if let Some(setting) = flat.remove(*key) {
+            playback.insert(key.to_string(), setting);
+        }
+    }
+    flat.insert("playback".to_string(), Value::Object(playback));
+    Value::Object(flat)
+}
@@ -0,0 +1,210 @@
+use std::time;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    After(time::Duration),
+    EndOfChapter,
+}
+
+/// Countdown to pausing playback. Only time spent playing counts, so a
+/// paused book does not fall asleep behind the listener's back.
+#[derive(Debug, Clone)]
+pub struct SleepTimer {
+    mode: Mode,
+    fade: time::Duration,
+    reset_on_interaction: bool,
+    remaining: time::Duration,
+    ticked: Option<time::Instant>,
+    chapter_end: Option<time::Duration>,
+}
+
+impl SleepTimer {
+    pub fn new(mode: Mode) -> SleepTimer {
+        SleepTimer {
+            mode,
+            fade: time::Duration::from_secs(0),
+            reset_on_interaction: false,
+            remaining: match mode {
+                Mode::After(duration) => duration,
+                Mode::EndOfChapter => time::Duration::from_secs(0),
+            },
+            ticked: None,
+            chapter_end: None,
+        }
+    }
+
+    /// Lowers the volume gradually during the last `fade` of the countdown.
+    pub fn fade(mut self, fade: time::Duration) -> SleepTimer {
+        self.fade = fade;
+        self
+    }
+
+    /// Restarts the countdown whenever the listener touches the controls.
+    pub fn reset_on_interaction(mut self, reset: bool) -> SleepTimer {
+        self.reset_on_interaction = reset;
+        self
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    pub fn remaining(&self) -> time::Duration {
+        self.remaining
+    }
+
+    /// Adds time to the countdown. An end-of-chapter timer becomes a plain
+    /// countdown from whatever was left of the chapter.
+    pub fn extend(&mut self, by: time::Duration) {
+        self.remaining += by;
+        self.mode = Mode::After(self.remaining);
+    }
+
+    pub fn interact(&mut self) {
+        if let (true, Mode::After(duration)) = (self.reset_on_interaction, self.mode) {
+            self.remaining = duration;
+        }
+    }
+
+    pub fn pause(&mut self) {
+        self.ticked = None;
+    }
+
+    /// Advances the countdown and returns what is left of it. `chapter_end`
+    /// on the first tick is where an end-of-chapter timer stops, the end of
+    /// the book when no chapter is playing.
+    pub fn tick(
+        &mut self,
+        position: time::Duration,
+        chapter_end: time::Duration,
+        rate: f64,
+    ) -> time::Duration {
+        let now = time::Instant::now();
+
+        match self.mode {
+            Mode::After(_) => {
+                if let Some(ticked) = self.ticked {
+                    let elapsed = now.duration_since(ticked);
+                    self.remaining = if self.remaining > elapsed {
+                        self.remaining - elapsed
+                    } else {
+                        time::Duration::from_secs(0)
+                    };
+                }
+            }
+            Mode::EndOfChapter => {
+                if self.chapter_end.is_none() {
+                    self.chapter_end = Some(chapter_end);
+                }
+                self.remaining = match self.chapter_end {
+                    Some(end) if end > position => from_seconds(seconds(end - position) / rate),
+                    _ => time::Duration::from_secs(0),
+                };
+            }
+        }
+
+        self.ticked = Some(now);
+        self.remaining
+    }
+
+    /// Volume factor for the current point of the countdown, from 1.0 down
+    /// to 0.0 across the fade-out.
+    pub fn volume(&self) -> f64 {
+        if self.remaining >= self.fade {
+            1.0
+        } else {
+            seconds(self.remaining) / seconds(self.fade)
+        }
+    }
+}
+
+pub fn seconds(duration: time::Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1e9
+}
+
+pub fn from_seconds(seconds: f64) -> time::Duration {
+    let seconds = seconds.max(0.0);
+    time::Duration::new(seconds.trunc() as u64, (seconds.fract() * 1e9) as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time;
+
+    use super::{Mode, SleepTimer};
+
+    fn secs(secs: u64) -> time::Duration {
+        time::Duration::from_secs(secs)
+    }
+
+    /// Lets a little playing time pass between ticks.
+    fn play_a_little() {
+        thread::sleep(time::Duration::from_millis(20));
+    }
+
+    #[test]
+    fn counts_down_only_while_playing() {
+        let mut timer = SleepTimer::new(Mode::After(secs(60)));
+        assert_eq!(timer.tick(secs(0), secs(600), 1.0), secs(60));
+        play_a_little();
+        assert!(timer.tick(secs(0), secs(600), 1.0) < secs(60));
+
+        timer.pause();
+        let left = timer.remaining();
+        play_a_little();
+        assert_eq!(timer.tick(secs(0), secs(600), 1.0), left);
+    }
+
+    #[test]
+    fn waits_for_the_chapter_playing_at_the_first_tick() {
+        let mut timer = SleepTimer::new(Mode::EndOfChapter);
+        assert_eq!(timer.tick(secs(10), secs(70), 2.0), secs(30));
+        assert_eq!(timer.tick(secs(40), secs(200), 2.0), secs(15));
+        assert_eq!(timer.tick(secs(80), secs(200), 2.0), secs(0));
+    }
+
+    #[test]
+    fn runs_to_the_end_of_the_book_without_chapters() {
+        let mut timer = SleepTimer::new(Mode::EndOfChapter);
+        assert_eq!(timer.tick(secs(0), secs(3600), 1.0), secs(3600));
+    }
+
+    #[test]
+    fn fades_out_over_the_last_seconds() {
+        let mut timer = SleepTimer::new(Mode::After(secs(5))).fade(secs(10));
+        timer.tick(secs(0), secs(600), 1.0);
+        assert!((timer.volume() - 0.5).abs() < 0.01);
+
+        let timer = SleepTimer::new(Mode::After(secs(60))).fade(secs(10));
+        assert_eq!(timer.volume(), 1.0);
+    }
+
+    #[test]
+    fn extends_end_of_chapter_into_a_countdown() {
+        let mut timer = SleepTimer::new(Mode::EndOfChapter);
+        timer.tick(secs(10), secs(40), 1.0);
+        timer.extend(secs(60));
+        assert_eq!(timer.remaining(), secs(90));
+        assert_eq!(timer.mode(), Mode::After(secs(90)));
+        let left = timer.tick(secs(50), secs(600), 1.0);
+        assert!(left > secs(89) && left <= secs(90));
+    }
+
+    #[test]
+    fn resets_on_interaction_when_asked() {
+        let mut timer = SleepTimer::new(Mode::After(secs(60))).reset_on_interaction(true);
+        timer.tick(secs(0), secs(600), 1.0);
+        play_a_little();
+        timer.tick(secs(0), secs(600), 1.0);
+        timer.interact();
+        assert_eq!(timer.remaining(), secs(60));
+
+        let mut timer = SleepTimer::new(Mode::After(secs(60)));
+        timer.tick(secs(0), secs(600), 1.0);
+        play_a_little();
+        timer.tick(secs(0), secs(600), 1.0);
+        timer.interact();
+        assert!(timer.remaining() < secs(60));
+    }
+}
@@ -0,0 +1,234 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path;
+use std::time;
+
+use glib;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json;
+
+use errors::{Error, ErrorKind, Result};
+
+/// What we remember about a book between sessions.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Record {
+    pub position: time::Duration,
+    pub chapter: Option<usize>,
+    #[serde(default = "default_rate")]
+    pub rate: f64,
+    #[serde(default = "never")]
+    pub updated: time::SystemTime,
+}
+
+fn default_rate() -> f64 {
+    1.0
+}
+
+fn never() -> time::SystemTime {
+    time::UNIX_EPOCH
+}
+
+/// Per-book records keyed by `Book::id`, kept as JSON in the user data dir.
+pub struct Store {
+    path: path::PathBuf,
+    books: HashMap<String, Record>,
+    /// Cleared when the file on disk could not be read nor moved aside.
+    writable: bool,
+    /// What went wrong reading the file, for the listener to see.
+    problem: Option<String>,
+}
+
+impl Store {
+    pub fn open() -> Store {
+        let path = data_dir().join("books.json");
+        let (books, problem) = recover_json(&path);
+        Store {
+            path,
+            writable: books.is_some(),
+            books: books.unwrap_or_default(),
+            problem: problem.map(|err| err.to_string()),
+        }
+    }
+
+    pub fn load(path: &path::Path) -> Result<Store> {
+        Ok(Store {
+            path: path.to_path_buf(),
+            books: read_json(path)?.unwrap_or_default(),
+            writable: true,
+            problem: None,
+        })
+    }
+
+    pub fn problem(&self) -> Option<&str> {
+        self.problem.as_ref().map(|problem| problem.as_str())
+    }
+
+    pub fn get(&self, book: &str) -> Option<&Record> {
+        self.books.get(book)
+    }
+
+    pub fn records(&self) -> &HashMap<String, Record> {
+        &self.books
+    }
+
+    pub fn set(&mut self, book: &str, record: Record) {
+        self.books.insert(book.to_string(), record);
+    }
+
+    pub fn save(&self) -> Result<()> {
+        if !self.writable {
+            bail!(ErrorKind::Protected(self.path.clone()));
+        }
+        write_json(&self.path, &self.books)
+    }
+}
+
+/// Reads a JSON file the application keeps for itself. One that cannot be
+/// read is set aside, so the next save starts over instead of destroying
+/// it. Comes back with what went wrong, and with no data at all when even
+/// setting the file aside failed and it must be left alone.
+pub fn recover_json<T: DeserializeOwned + Default>(path: &path::Path) -> (Option<T>, Option<Error>) {
+    match read_json(path) {
+        Ok(value) => (Some(value.unwrap_or_default()), None),
+        Err(err) => match set_aside(path) {
+            Ok(aside) => (
+                Some(T::default()),
+                Some(ErrorKind::SetAside(path.to_path_buf(), aside, err.to_string()).into()),
+            ),
+            Err(_) => (
+                None,
+                Some(ErrorKind::Unreadable(path.to_path_buf(), err.to_string()).into()),
+            ),
+        },
+    }
+}
+
+/// Moves an unreadable file to `<name>.corrupt`, where it can still be
+/// recovered by hand.
+pub fn set_aside(path: &path::Path) -> Result<path::PathBuf> {
+    let mut aside = path.as_os_str().to_owned();
+    aside.push(".corrupt");
+    fs::rename(path, &aside)?;
+    Ok(path::PathBuf::from(aside))
+}
+
+/// Reads a JSON file, treating a missing file as no data yet.
+pub fn read_json<T: DeserializeOwned>(path: &path::Path) -> Result<Option<T>> {
+    match fs::File::open(path) {
+        Ok(file) => Ok(Some(serde_json::from_reader(file)?)),
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+pub fn write_json<T: Serialize>(path: &path::Path, value: &T) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    // Write to a sibling file first so a crash never leaves half a file.
+    let partial = path.with_extension("json.partial");
+    serde_json::to_writer(fs::File::create(&partial)?, value)?;
+    fs::rename(&partial, path)?;
+    Ok(())
+}
+
+pub fn data_dir() -> path::PathBuf {
+    glib::get_user_data_dir()
+        .unwrap_or_else(|| path::PathBuf::from("."))
+        .join("librebooks")
+}
+
+pub fn config_dir() -> path::PathBuf {
+    glib::get_user_config_dir()
+        .unwrap_or_else(|| path::PathBuf::from("."))
+        .join("librebooks")
+}
+
+pub fn cache_dir() -> path::PathBuf {
+    glib::get_user_cache_dir()
+        .unwrap_or_else(|| path::PathBuf::from("."))
+        .join("librebooks")
+}
+
+/// Serializes paths as strings, or as their bytes when they are not valid
+/// UTF-8, which `PathBuf` itself refuses. For `#[serde(with = "...")]`.
+pub mod os_path {
+    use std::os::unix::ffi::{OsStrExt, OsStringExt};
+    use std::ffi::OsString;
+    use std::path;
+
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    #[derive(Serialize, Deserialize)]
+    #[serde(untagged)]
+    enum Encoded {
+        Text(String),
+        Bytes(Vec<u8>),
+    }
+
+    fn encode(path: &path::Path) -> Encoded {
+        match path.to_str() {
+            Some(text) => Encoded::Text(text.to_string()),
+            None => Encoded::Bytes(path.as_os_str().as_bytes().to_vec()),
+        }
+    }
+
+    fn decode(encoded: Encoded) -> path::PathBuf {
+        match encoded {
+            Encoded::Text(text) => path::PathBuf::from(text),
+            Encoded::Bytes(bytes) => path::PathBuf::from(OsString::from_vec(bytes)),
+        }
+    }
+
+    pub fn serialize<S: Serializer>(path: &path::PathBuf, serializer: S) -> Result<S::Ok, S::Error> {
+        encode(path).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<path::PathBuf, D::Error> {
+        Encoded::deserialize(deserializer).map(decode)
+    }
+
+    pub mod list {
+        use std::path;
+
+        use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+        pub fn serialize<S: Serializer>(
+            paths: &[path::PathBuf],
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            let encoded: Vec<super::Encoded> = paths.iter().map(|path| super::encode(path)).collect();
+            encoded.serialize(serializer)
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Vec<path::PathBuf>, D::Error> {
+            let encoded: Vec<super::Encoded> = Deserialize::deserialize(deserializer)?;
+            Ok(encoded.into_iter().map(super::decode).collect())
+        }
+    }
+
+    pub mod option {
+        use std::path;
+
+        use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+        pub fn serialize<S: Serializer>(
+            path: &Option<path::PathBuf>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            path.as_ref().map(|path| super::encode(path)).serialize(serializer)
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Option<path::PathBuf>, D::Error> {
+            let encoded: Option<super::Encoded> = Deserialize::deserialize(deserializer)?;
+            Ok(encoded.map(super::decode))
+        }
+    }
+}
@@ -0,0 +1,117 @@
+use std::env;
+use std::path;
+
+use gio::{self, FileExt};
+use glib;
+
+use errors::{ErrorKind, Result};
+
+/// Turns a path into a `file://` URI, percent-encoding whatever needs it,
+/// including names that are not valid UTF-8. Relative paths are taken from
+/// the current directory.
+pub fn from_path(path: &path::Path) -> Result<String> {
+    let absolute = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        env::current_dir()?.join(path)
+    };
+    Ok(glib::filename_to_uri(&absolute, None)?)
+}
+
+/// Accepts either a `file://` URI or a plain path, as given on a command
+/// line or by a desktop shell. URIs of other schemes are refused, books are
+/// read from local files.
+pub fn to_path(input: &str) -> Result<path::PathBuf> {
+    match glib::uri_parse_scheme(input) {
+        Some(ref scheme) if scheme == "file" => {
+            let (path, _) = glib::filename_from_uri(input)?;
+            Ok(path)
+        }
+        Some(_) => bail!(ErrorKind::NotLocal(input.to_string())),
+        None => Ok(path::PathBuf::from(input)),
+    }
+}
+
+/// Local path of a file handed over by GIO, as a file chooser or a drop
+/// does. Files GIO only knows by their URI are taken through `to_path`.
+pub fn from_file(file: &gio::File) -> Result<path::PathBuf> {
+    match file.get_path() {
+        Some(path) => Ok(path),
+        None => to_path(&file.get_uri()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path;
+
+    use gio;
+
+    use super::{from_file, from_path, to_path};
+    use errors::ErrorKind;
+
+    fn round_trip(path: &path::Path) -> String {
+        let uri = from_path(path).unwrap();
+        assert_eq!(to_path(&uri).unwrap(), path);
+        uri
+    }
+
+    #[test]
+    fn round_trips_tricky_names() {
+        assert_eq!(
+            round_trip(path::Path::new("/books/The Long Book.m4b")),
+            "file:///books/The%20Long%20Book.m4b"
+        );
+        assert!(round_trip(path::Path::new("/books/Part #1.mp3")).ends_with("/Part%20%231.mp3"));
+        assert!(round_trip(path::Path::new("/books/100% Pure.mp3")).ends_with("/100%25%20Pure.mp3"));
+        assert!(round_trip(path::Path::new("/books/Why?.mp3")).ends_with("/Why%3F.mp3"));
+
+        let latin1 = path::Path::new(OsStr::from_bytes(b"/books/Caf\xe9.mp3"));
+        assert!(round_trip(latin1).ends_with("/Caf%E9.mp3"));
+    }
+
+    #[test]
+    fn makes_relative_paths_absolute() {
+        let uri = from_path(path::Path::new("book.mp3")).unwrap();
+        assert!(uri.starts_with("file:///"));
+        assert!(uri.ends_with("/book.mp3"));
+    }
+
+    #[test]
+    fn accepts_uris_and_plain_paths() {
+        assert_eq!(
+            to_path("file:///books/The%20Long%20Book.m4b").unwrap(),
+            path::Path::new("/books/The Long Book.m4b")
+        );
+        assert_eq!(
+            to_path("/books/The Long Book.m4b").unwrap(),
+            path::Path::new("/books/The Long Book.m4b")
+        );
+        assert_eq!(to_path("book.mp3").unwrap(), path::Path::new("book.mp3"));
+    }
+
+    #[test]
+    fn takes_gio_files_by_path_or_uri() {
+        let file = gio::File::new_for_path("/books/The Long Book.m4b");
+        assert_eq!(from_file(&file).unwrap(), path::Path::new("/books/The Long Book.m4b"));
+
+        let file = gio::File::new_for_uri("file:///books/Caf%E9.mp3");
+        assert_eq!(
+            from_file(&file).unwrap(),
+            path::Path::new(OsStr::from_bytes(b"/books/Caf\xe9.mp3"))
+        );
+    }
+
+    #[test]
+    fn refuses_remote_uris() {
+        match to_path("http://example.com/book.mp3") {
+            Err(err) => match *err.kind() {
+                ErrorKind::NotLocal(ref uri) => assert_eq!(uri, "http://example.com/book.mp3"),
+                ref kind => panic!("unexpected error {}", kind),
+            },
+            Ok(path) => panic!("accepted as {}", path.display()),
+        }
+    }
+}
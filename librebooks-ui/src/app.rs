@@ -1,15 +1,24 @@
-use std::ops::{Add, Sub};
+use std::ops::Add;
+use std::path;
 use std::sync::mpsc;
 use std::thread;
 use std::time;
 
 use relm::{Channel, Relm, Update, Widget};
 
+use gio::FileExt;
 use glib::translate::FromGlib;
 use gtk::prelude::*;
 
+use core::library::{Entry, Library};
+use core::mpris;
 use core::player;
+use core::uri;
+use core::{Bookmark, Bookmarks, Settings};
+use covers;
 use errors::Result;
+use library::LibraryView;
+use preferences;
 use resources;
 
 pub struct Model {}
@@ -18,22 +27,79 @@ pub struct Model {}
 pub enum Msg {
     Quit,
     Open,
+    ShowPreferences,
     TogglePlay,
     NextChapter,
     PreviousChaper,
     SkipForward,
     SkipBackward,
+    ScrubStart,
+    Scrub(f64),
+    ScrubEnd,
+    ToggleWholeBook,
     PlayerEvent(player::Event),
     ShowChapters,
+    JumpToChapter(i32),
+    SetChapterLevel(i32),
+    ShowBookmarks,
+    AddBookmark,
+    JumpToBookmark(u64),
+    EditBookmark(u64),
+    DeleteBookmark(u64),
+    ShowRates,
+    SetRate(f64),
+    ShowSleep,
+    SleepAfter(u64),
+    SleepAtChapterEnd,
+    ExtendSleep,
+    CancelSleep,
+    Remote(mpris::Command),
+    DismissMessage,
+    ShowLibrary,
+    RefreshLibrary,
+    RescanLibrary,
+    LibraryScanned(Vec<Entry>),
+    OpenFromLibrary(i32),
 }
 
+pub const RATES: &[f64] = &[0.75, 1.0, 1.25, 1.5, 1.75, 2.0, 2.5, 3.0];
+
+const ARTWORK_SIZE: i32 = 300;
+
+/// Column of the chapter tree holding the index into the book's chapters.
+const CHAPTER_INDEX_COLUMN: i32 = 3;
+
+/// Going back within this many seconds of a chapter start goes to the one
+/// before.
+const RESTART_CHAPTER_SECS: u64 = 10;
+
+/// Names of the chapter levels, from the top, for books nesting no deeper.
+const LEVEL_NAMES: &[&str] = &["part", "chapter", "section"];
+
 pub struct Application {
+    relm: Relm<Application>,
     resources: resources::MainWindow,
-    player: player::Player,
+    player: mpsc::Sender<player::Command>,
     state: player::State,
     metadata: player::Metadata,
     position: time::Duration,
+    rate: f64,
+    whole_book: bool,
+    scrub: Option<Scrub>,
     chapters: gtk::Popover,
+    chapter_tree: gtk::TreeView,
+    chapter_store: gtk::TreeStore,
+    chapter_iters: Vec<gtk::TreeIter>,
+    chapter_levels: gtk::ComboBoxText,
+    chapter_level: usize,
+    chapter: Option<usize>,
+    bookmarks: gtk::Popover,
+    bookmark_store: Bookmarks,
+    rates: gtk::Popover,
+    sleep: gtk::Popover,
+    mpris: Option<mpris::Mpris>,
+    library: LibraryView,
+    settings: Settings,
 }
 
 use chrono::prelude::*;
@@ -46,6 +112,20 @@ pub fn formatted_date(dt: chrono::DateTime<Utc>) -> String {
     )
 }
 
+fn clock(duration: time::Duration) -> String {
+    Utc.timestamp(duration.as_secs() as i64, 0)
+        .format("%H:%M:%S")
+        .to_string()
+}
+
+/// The stretch of the timeline under the seek bar while it is dragged, and
+/// where the drag would land.
+struct Scrub {
+    start: time::Duration,
+    end: time::Duration,
+    target: Option<time::Duration>,
+}
+
 enum SeekDirection {
     Forward(time::Duration),
     Backward(time::Duration),
@@ -54,85 +134,422 @@ enum SeekDirection {
 
 impl Application {
     fn switch_book(&mut self, metadata: player::Metadata) {
+        self.resources.message_bar.hide();
         self.resources.title.set_text(&metadata.title);
+        self.show_artwork(&metadata);
         self.metadata = metadata;
-        self.player.play();
+        self.send(player::Command::Play);
+
+        self.refresh_chapters();
+        self.refresh_bookmarks();
+    }
+
+    fn refresh_chapters(&mut self) {
+        self.chapter_store.clear();
+        self.chapter_iters.clear();
+        for (index, chapter) in self.metadata.chapters.iter().enumerate() {
+            let iter = {
+                let parent = chapter.parent.and_then(|parent| self.chapter_iters.get(parent));
+                self.chapter_store.insert_with_values(
+                    parent,
+                    None,
+                    &[0, 1, 2, 3],
+                    &[
+                        &chapter.title,
+                        &clock(chapter.start),
+                        &clock(chapter.end - chapter.start),
+                        &(index as u32),
+                    ],
+                )
+            };
+            self.chapter_iters.push(iter);
+        }
+
+        // Skipping goes by the innermost chapters until told otherwise.
+        let depth = self.metadata.depth();
+        self.chapter_level = depth;
+        self.chapter_levels.remove_all();
+        for level in 0..depth + 1 {
+            let name = match LEVEL_NAMES.get(level) {
+                Some(name) if depth < LEVEL_NAMES.len() => name.to_string(),
+                _ => format!("level {}", level + 1),
+            };
+            self.chapter_levels
+                .append_text(format!("Skip by {}", name).as_str());
+        }
+        self.chapter_levels.set_active(depth as i32);
+        self.chapter_levels.set_visible(depth > 0);
 
-        for child in self.chapters.get_children().iter() {
-            self.chapters.remove(child);
+        self.chapter = None;
+        let position = self.position;
+        self.highlight_chapter(position);
+    }
+
+    /// Selects the row of the chapter playing at `position`, opening the
+    /// parts around it, and only touching the tree when the chapter changes.
+    fn highlight_chapter(&mut self, position: time::Duration) {
+        let current = self.metadata.chapter_at(position);
+        if current == self.chapter {
+            return;
         }
-        let vbox = gtk::ListBox::new();
+        self.chapter = current;
 
-        for chapter in self.metadata.chapters.iter() {
-            let label = gtk::Button::new_with_label(&chapter.title);
-            vbox.add(&label);
+        let selection = self.chapter_tree.get_selection();
+        match current.and_then(|index| self.chapter_iters.get(index)) {
+            Some(iter) => {
+                if let Some(path) = self.chapter_store.get_path(iter) {
+                    self.chapter_tree.expand_to_path(&path);
+                    self.chapter_tree.scroll_to_cell(
+                        Some(&path),
+                        None::<&gtk::TreeViewColumn>,
+                        false,
+                        0.0,
+                        0.0,
+                    );
+                }
+                selection.select_iter(iter);
+            }
+            None => selection.unselect_all(),
         }
+    }
 
-        self.chapters.add(&vbox);
+    fn show_chapters(&self) {
+        self.chapters.popup();
+        self.chapter_tree.grab_focus();
+    }
+
+    fn set_chapter_level(&mut self, level: i32) {
+        // The combo reports -1 while it is being refilled.
+        if level >= 0 {
+            self.chapter_level = level as usize;
+        }
+    }
+
+    fn jump_to_chapter(&mut self, index: i32) {
+        let start = match self.metadata.chapters.get(index as usize) {
+            Some(chapter) if index >= 0 => chapter.start,
+            _ => return,
+        };
+        self.chapters.popdown();
+        self.seek(SeekDirection::At(start));
+    }
+
+    fn show_artwork(&self, metadata: &player::Metadata) {
+        let cover = match (self.book_id(), metadata.artwork.as_ref()) {
+            (Some(book), Some(artwork)) => covers::thumbnail(&book, artwork, ARTWORK_SIZE),
+            _ => None,
+        };
+
+        let image = &self.resources.artwork;
+        match cover {
+            Some(cover) => image.set_from_pixbuf(Some(&cover)),
+            None => {
+                image.set_from_icon_name("audio-x-generic", gtk::IconSize::Dialog.into());
+                image.set_pixel_size(ARTWORK_SIZE / 2);
+            }
+        }
+    }
+
+    fn add_bookmark(&mut self) {
+        let book = match self.book_id() {
+            Some(book) => book,
+            None => return,
+        };
+
+        let title = match self.chapter_at(self.position) {
+            Some(chapter) => format!("{} ({})", chapter.title, clock(self.position - chapter.start)),
+            None => clock(self.position),
+        };
+
+        if self
+            .bookmark_store
+            .create(&book, self.position, &title)
+            .is_ok()
+        {
+            self.refresh_bookmarks();
+        }
+    }
+
+    fn refresh_bookmarks(&mut self) {
+        for child in self.bookmarks.get_children().iter() {
+            self.bookmarks.remove(child);
+        }
+        let list = gtk::ListBox::new();
+        list.set_selection_mode(gtk::SelectionMode::None);
+
+        if let Some(book) = self.book_id() {
+            for bookmark in self.bookmark_store.list(&book) {
+                list.add(&self.bookmark_row(&bookmark));
+            }
+        }
+
+        list.show_all();
+        self.bookmarks.add(&list);
+    }
+
+    fn bookmark_row(&self, bookmark: &Bookmark) -> gtk::Box {
+        let id = bookmark.id;
+        let row = gtk::Box::new(gtk::Orientation::Horizontal, 6);
+
+        let jump = gtk::Button::new_with_label(&clock(bookmark.position));
+        jump.set_relief(gtk::ReliefStyle::None);
+        connect!(self.relm, jump, connect_clicked(_), Msg::JumpToBookmark(id));
+        row.pack_start(&jump, false, true, 0);
+
+        let text = gtk::Box::new(gtk::Orientation::Vertical, 0);
+        let title = gtk::Label::new(Some(bookmark.title.as_str()));
+        title.set_halign(gtk::Align::Start);
+        text.pack_start(&title, false, true, 0);
+        if !bookmark.note.is_empty() {
+            let note = gtk::Label::new(Some(bookmark.note.as_str()));
+            note.set_halign(gtk::Align::Start);
+            note.set_line_wrap(true);
+            if let Some(context) = note.get_style_context() {
+                context.add_class("dim-label");
+            }
+            text.pack_start(&note, false, true, 0);
+        }
+        row.pack_start(&text, true, true, 0);
+
+        let edit = gtk::Button::new_from_icon_name(
+            "document-edit-symbolic",
+            gtk::IconSize::Button.into(),
+        );
+        edit.set_relief(gtk::ReliefStyle::None);
+        connect!(self.relm, edit, connect_clicked(_), Msg::EditBookmark(id));
+        row.pack_start(&edit, false, true, 0);
+
+        let delete = gtk::Button::new_from_icon_name(
+            "edit-delete-symbolic",
+            gtk::IconSize::Button.into(),
+        );
+        delete.set_relief(gtk::ReliefStyle::None);
+        connect!(self.relm, delete, connect_clicked(_), Msg::DeleteBookmark(id));
+        row.pack_start(&delete, false, true, 0);
+
+        row
+    }
+
+    fn jump_to_bookmark(&mut self, id: u64) {
+        if let Some(position) = self.bookmark_store.get(id).map(|bookmark| bookmark.position) {
+            self.bookmarks.popdown();
+            self.seek(SeekDirection::At(position));
+        }
+    }
+
+    fn edit_bookmark(&mut self, id: u64) {
+        let bookmark = match self.bookmark_store.get(id) {
+            Some(bookmark) => bookmark.clone(),
+            None => return,
+        };
+
+        let dialog = gtk::Dialog::new_with_buttons(
+            Some("Edit bookmark"),
+            Some(&self.resources.view),
+            gtk::DialogFlags::MODAL,
+            &[
+                ("Cancel", gtk::ResponseType::Cancel),
+                ("Save", gtk::ResponseType::Accept),
+            ],
+        );
+
+        let content = dialog.get_content_area();
+        content.set_spacing(6);
+        content.set_border_width(12);
+
+        let title = gtk::Entry::new();
+        title.set_text(&bookmark.title);
+        content.pack_start(&title, false, true, 0);
+
+        let note = gtk::TextView::new();
+        note.set_wrap_mode(gtk::WrapMode::Word);
+        note.set_size_request(300, 120);
+        if let Some(buffer) = note.get_buffer() {
+            buffer.set_text(&bookmark.note);
+        }
+        content.pack_start(&note, true, true, 0);
+        content.show_all();
+
+        if gtk::ResponseType::from_glib(dialog.run()) == gtk::ResponseType::Accept {
+            let title = title.get_text().unwrap_or_default();
+            let note = note
+                .get_buffer()
+                .and_then(|buffer| {
+                    let (start, end) = buffer.get_bounds();
+                    buffer.get_text(&start, &end, false)
+                })
+                .unwrap_or_default();
+
+            if self.bookmark_store.rename(id, &title).is_ok()
+                && self.bookmark_store.annotate(id, &note).is_ok()
+            {
+                self.refresh_bookmarks();
+            }
+        }
+
+        dialog.close();
+    }
+
+    fn delete_bookmark(&mut self, id: u64) {
+        if self.bookmark_store.delete(id).is_ok() {
+            self.refresh_bookmarks();
+        }
     }
 
     fn next_chapter(&mut self) {
-        if let Some(chapter) = self.chapter_at(self.position.clone()) {
-            self.seek(SeekDirection::At(chapter.end));
+        let position = self.position;
+        if let Some(start) = self.metadata.next_chapter(position, self.chapter_level) {
+            self.seek(SeekDirection::At(start));
         }
     }
 
     fn previous_chaper(&mut self) {
-        if let Some(chapter) = self.chapter_at(self.position.clone()) {
-            if self.position.as_secs() - chapter.start.as_secs() < 10 {
-                if let Some(chapter) =
-                    self.chapter_at(chapter.start - time::Duration::from_secs(10))
-                {
-                    self.seek(SeekDirection::At(chapter.start));
-                }
-            } else {
-                self.seek(SeekDirection::At(chapter.start));
-            }
+        let position = self.position;
+        let grace = time::Duration::from_secs(RESTART_CHAPTER_SECS);
+        if let Some(start) = self
+            .metadata
+            .previous_chapter(position, self.chapter_level, grace)
+        {
+            self.seek(SeekDirection::At(start));
         }
     }
 
     fn seek(&mut self, seek: SeekDirection) {
         let position = match seek {
             SeekDirection::Forward(delta) => self.position.add(delta),
-            SeekDirection::Backward(delta) => self.position.sub(delta),
+            SeekDirection::Backward(delta) => self
+                .position
+                .checked_sub(delta)
+                .unwrap_or(time::Duration::from_secs(0)),
             SeekDirection::At(position) => position.clone(),
         };
         //self.chapters.append(());
-        self.player.seek(position);
+        self.send(player::Command::Seek(position));
     }
 
     fn update_progress(&mut self, clock: time::Duration) {
         self.position = clock.clone();
+        self.highlight_chapter(clock);
 
         if let Some(chapter) = self.chapter_at(clock) {
-            let position = (clock - chapter.start).as_secs() as f64;
-            let total = (chapter.end - chapter.start).as_secs() as f64;
-            let fraction = position / total;
+            self.resources.chapter.set_label(&chapter.title);
+        }
+        // The bar belongs to the listener while it is being dragged.
+        if self.scrub.is_some() {
+            return;
+        }
+
+        if let Some((start, end)) = self.progress_range(clock) {
+            let position = (clock - start).as_secs() as f64;
+            let total = (end - start).as_secs() as f64;
+            let fraction = if total > 0.0 { position / total } else { 0.0 };
 
-            self.resources.progress.set_fraction(fraction);
+            self.resources.progress.set_value(fraction);
             let dt = Utc.timestamp(position as i64, 0);
 
             self.resources.played.set_markup(&formatted_date(dt));
 
-            let dt = Utc.timestamp((total - position) as i64, 0);
+            let dt = Utc.timestamp(((total - position) / self.rate) as i64, 0);
 
             self.resources.remaining.set_markup(&formatted_date(dt));
-            self.resources.chapter.set_label(&chapter.title);
         }
     }
 
+    /// What the seek bar spans: the chapter playing at `position`, or the
+    /// whole book.
+    fn progress_range(&self, position: time::Duration) -> Option<(time::Duration, time::Duration)> {
+        if self.whole_book {
+            Some((time::Duration::from_secs(0), self.metadata.duration))
+        } else {
+            self.chapter_at(position)
+                .map(|chapter| (chapter.start, chapter.end))
+        }
+    }
+
+    fn start_scrub(&mut self) {
+        let position = self.position;
+        self.scrub = self.progress_range(position).map(|(start, end)| Scrub {
+            start,
+            end,
+            target: None,
+        });
+    }
+
+    /// Previews where letting go of the seek bar would land.
+    fn scrub(&mut self, fraction: f64) {
+        let target = match self.scrub {
+            Some(ref mut scrub) => {
+                let span = (scrub.end - scrub.start).as_secs() as f64 * fraction.max(0.0).min(1.0);
+                let target = scrub.start + time::Duration::from_secs(span as u64);
+                scrub.target = Some(target);
+                target
+            }
+            None => return,
+        };
+
+        let title = self
+            .chapter_at(target)
+            .map(|chapter| chapter.title)
+            .unwrap_or_default();
+        self.resources
+            .progress
+            .set_tooltip_text(Some(format!("{}  {}", clock(target), title).as_str()));
+
+        let start = self.scrub.as_ref().map(|scrub| scrub.start).unwrap_or(target);
+        let dt = Utc.timestamp((target - start).as_secs() as i64, 0);
+        self.resources.played.set_markup(&formatted_date(dt));
+    }
+
+    fn end_scrub(&mut self) {
+        self.resources.progress.set_tooltip_text(None::<&str>);
+        if let Some(target) = self.scrub.take().and_then(|scrub| scrub.target) {
+            self.seek(SeekDirection::At(target));
+        }
+    }
+
+    fn show_preferences(&mut self) {
+        if let Some(settings) = preferences::run(&self.resources.view, &self.settings) {
+            self.settings = settings;
+            if let Err(err) = self.settings.save() {
+                let markup = format!(
+                    "<b>Preferences not saved</b>\n{}",
+                    glib::markup_escape_text(&err.to_string())
+                );
+                self.show_message(gtk::MessageType::Warning, &markup);
+            }
+            self.reflect_on_settings();
+        }
+    }
+
+    fn reflect_on_settings(&self) {
+        let playback = &self.settings.playback;
+        self.resources.skip_forward.set_tooltip_text(Some(
+            format!("Forward {} seconds", playback.skip_forward_secs).as_str(),
+        ));
+        self.resources.skip_backward.set_tooltip_text(Some(
+            format!("Back {} seconds", playback.skip_backward_secs).as_str(),
+        ));
+        self.send(player::Command::ApplySettings(self.settings.clone()));
+        self.build_sleep();
+        preferences::apply_theme(self.settings.appearance.theme);
+    }
+
+    fn toggle_whole_book(&mut self) {
+        self.whole_book = self.resources.whole_book.get_active();
+        let position = self.position;
+        self.update_progress(position);
+    }
+
     fn chapter_at(&self, position: time::Duration) -> Option<player::Chapter> {
         self.metadata
-            .chapters
-            .iter()
-            .find(|chapter| chapter.start <= position && position <= chapter.end)
-            .map(|chapter| chapter.clone())
+            .chapter_at(position)
+            .map(|chapter| self.metadata.chapters[chapter].clone())
     }
 
     fn toggle_play(&mut self) {
         match self.state {
-            Playing => self.player.pause(),
-            Paused => self.player.play(),
+            Playing => self.send(player::Command::Pause),
+            Paused => self.send(player::Command::Play),
             Stopped => self.open(),
             _ => {}
         }
@@ -142,6 +559,230 @@ impl Application {
         self.state = state;
     }
 
+    fn reflect_on_rate(&mut self, rate: f64) {
+        self.rate = rate;
+        self.resources.rate.set_label(&format!("{}×", rate));
+        let position = self.position;
+        self.update_progress(position);
+    }
+
+    fn start_sleep(&mut self, mode: player::SleepMode) {
+        let timer = player::SleepTimer::new(mode)
+            .fade(time::Duration::from_secs(self.settings.sleep.fade_secs))
+            .reset_on_interaction(self.settings.sleep.reset_on_interaction);
+        self.send(player::Command::SetSleepTimer(timer));
+    }
+
+    /// Shows a problem in the info bar above the player, naming the file that
+    /// caused it.
+    fn show_error(&self, error: &player::PlaybackError) {
+        let reason = glib::markup_escape_text(error.reason());
+        let markup = match error.path().and_then(|path| path.file_name()) {
+            Some(name) => format!(
+                "<b>{}</b>\n{}",
+                glib::markup_escape_text(&name.to_string_lossy()),
+                reason
+            ),
+            None => reason,
+        };
+        self.show_message(gtk::MessageType::Error, &markup);
+    }
+
+    fn show_warning(&self, problem: &str) {
+        self.show_message(gtk::MessageType::Warning, &glib::markup_escape_text(problem));
+    }
+
+    fn show_message(&self, kind: gtk::MessageType, markup: &str) {
+        self.resources.message.set_markup(markup);
+        self.resources.message_bar.set_message_type(kind);
+        self.resources.message_bar.show();
+    }
+
+    fn reflect_on_end(&mut self) {
+        let markup = format!(
+            "Finished <b>{}</b>",
+            glib::markup_escape_text(&self.metadata.title)
+        );
+        self.show_message(gtk::MessageType::Info, &markup);
+    }
+
+    fn reflect_on_buffering(&self, percent: i32) {
+        if percent < 100 {
+            let title = format!("{} ({}%)", self.metadata.title, percent);
+            self.resources.title.set_text(&title);
+        } else {
+            self.resources.title.set_text(&self.metadata.title);
+        }
+    }
+
+    fn reflect_on_duration(&mut self, duration: time::Duration) {
+        self.metadata.duration = duration;
+        let position = self.position;
+        self.update_progress(position);
+    }
+
+    fn reflect_on_sleep(&mut self, remaining: Option<time::Duration>) {
+        let label = remaining.map(clock).unwrap_or_default();
+        self.resources.sleep.set_label(&label);
+    }
+
+    fn build_sleep(&self) {
+        let (relm, sleep) = (&self.relm, &self.sleep);
+        for child in sleep.get_children().iter() {
+            sleep.remove(child);
+        }
+        let list = gtk::Box::new(gtk::Orientation::Vertical, 0);
+
+        for &minutes in self.settings.sleep.minutes.iter() {
+            let button = gtk::ModelButton::new();
+            button.set_property_text(Some(format!("{} minutes", minutes).as_str()));
+            connect!(relm, button, connect_clicked(_), Msg::SleepAfter(minutes));
+            list.add(&button);
+        }
+
+        let button = gtk::ModelButton::new();
+        button.set_property_text(Some("End of chapter"));
+        connect!(relm, button, connect_clicked(_), Msg::SleepAtChapterEnd);
+        list.add(&button);
+
+        list.add(&gtk::Separator::new(gtk::Orientation::Horizontal));
+
+        let button = gtk::ModelButton::new();
+        button.set_property_text(Some(
+            format!("{} more minutes", self.settings.sleep.extension_minutes).as_str(),
+        ));
+        connect!(relm, button, connect_clicked(_), Msg::ExtendSleep);
+        list.add(&button);
+
+        let button = gtk::ModelButton::new();
+        button.set_property_text(Some("Cancel"));
+        connect!(relm, button, connect_clicked(_), Msg::CancelSleep);
+        list.add(&button);
+
+        list.show_all();
+        sleep.add(&list);
+    }
+
+    /// The chapter tree sits in a scrolled window under the choice of what
+    /// the chapter buttons skip by, which only shows for nested chapters.
+    fn build_chapters(
+        relm: &Relm<Self>,
+        chapters: &gtk::Popover,
+    ) -> (gtk::TreeView, gtk::TreeStore, gtk::ComboBoxText) {
+        let store = gtk::TreeStore::new(&[
+            gtk::Type::String,
+            gtk::Type::String,
+            gtk::Type::String,
+            gtk::Type::U32,
+        ]);
+        let tree = gtk::TreeView::new_with_model(&store);
+        tree.set_headers_visible(false);
+        tree.get_selection().set_mode(gtk::SelectionMode::Single);
+
+        for column in 0..3 {
+            let cell = gtk::CellRendererText::new();
+            let view_column = gtk::TreeViewColumn::new();
+            view_column.pack_start(&cell, true);
+            view_column.add_attribute(&cell, "text", column);
+            if column == 0 {
+                cell.set_property_ellipsize(pango::EllipsizeMode::End);
+                cell.set_property_width_chars(40);
+                view_column.set_expand(true);
+            } else {
+                cell.set_property_xalign(1.0);
+            }
+            tree.append_column(&view_column);
+        }
+        connect!(
+            relm,
+            tree,
+            connect_row_activated(tree, path, _),
+            Msg::JumpToChapter(chapter_index(tree, path))
+        );
+
+        let levels = gtk::ComboBoxText::new();
+        levels.set_margin_bottom(6);
+        connect!(
+            relm,
+            levels,
+            connect_changed(levels),
+            Msg::SetChapterLevel(levels.get_active())
+        );
+
+        let scrolled = gtk::ScrolledWindow::new(None, None);
+        scrolled.set_policy(gtk::PolicyType::Never, gtk::PolicyType::Automatic);
+        scrolled.set_min_content_height(360);
+        scrolled.set_propagate_natural_width(true);
+        scrolled.add(&tree);
+
+        let content = gtk::Box::new(gtk::Orientation::Vertical, 0);
+        content.set_border_width(6);
+        content.pack_start(&levels, false, true, 0);
+        content.pack_start(&scrolled, true, true, 0);
+
+        content.show_all();
+        levels.hide();
+        chapters.add(&content);
+        (tree, store, levels)
+    }
+
+    fn build_rates(relm: &Relm<Self>, rates: &gtk::Popover) {
+        let list = gtk::Box::new(gtk::Orientation::Vertical, 0);
+        for &rate in RATES {
+            let button = gtk::ModelButton::new();
+            button.set_property_text(Some(format!("{}×", rate).as_str()));
+            connect!(relm, button, connect_clicked(_), Msg::SetRate(rate));
+            list.add(&button);
+        }
+        list.show_all();
+        rates.add(&list);
+    }
+
+    fn show_library(&mut self) {
+        self.library.load_records(&self.player);
+        self.library.refresh();
+        if self.library.is_empty() {
+            self.rescan_library();
+        }
+        self.library.resources.view.present();
+    }
+
+    /// Scans the library folders off the main thread, the catalog is
+    /// reloaded once the scan is done.
+    fn rescan_library(&self) {
+        let stream = self.relm.stream().clone();
+        let (_channel, sender) = Channel::new(move |books| {
+            stream.emit(Msg::LibraryScanned(books));
+        });
+
+        self.library.resources.rescan.set_sensitive(false);
+        let folders = self.settings.library.folders.clone();
+        thread::spawn(move || {
+            let mut library = Library::open();
+            library.scan(&folders).is_ok();
+            sender.send(library.books().to_vec()).is_ok();
+        });
+    }
+
+    fn reflect_on_scan(&mut self, books: Vec<Entry>) {
+        self.library.resources.rescan.set_sensitive(true);
+        self.library.set_books(books);
+        self.library.load_records(&self.player);
+        self.library.refresh();
+    }
+
+    /// Hands the files of a book to the player, which reports back with the
+    /// book or an error once they are read.
+    fn open_from_library(&mut self, index: i32) {
+        let files = match self.library.book_at(index) {
+            Some(entry) => entry.files.clone(),
+            None => return,
+        };
+        self.send(player::Command::OpenFiles(files));
+        self.library.resources.view.hide();
+        self.resources.view.present();
+    }
+
     fn open(&mut self) {
         let file_chooser = gtk::FileChooserDialog::with_buttons(
             Some("Open a media file"),
@@ -154,12 +795,25 @@ impl Application {
         );
 
         let filter = gtk::FileFilter::new();
-        filter.add_mime_type("audio/mpeg");
+        filter.set_name("Audiobooks");
+        for mime_type in player::AUDIO_MIME_TYPES {
+            filter.add_mime_type(mime_type);
+        }
+        for extension in player::AUDIO_EXTENSIONS {
+            filter.add_pattern(&format!("*.{}", extension));
+        }
         file_chooser.add_filter(&filter);
+        file_chooser.set_select_multiple(true);
 
         if gtk::ResponseType::from_glib(file_chooser.run()) == gtk::ResponseType::Accept {
-            if let Some(path) = file_chooser.get_filename() {
-                self.player.open(path);
+            let files = file_chooser.get_files();
+            if files.len() == 1 {
+                self.send(player::Command::OpenUri(files[0].get_uri()));
+            } else {
+                match local_paths(&files) {
+                    Ok(paths) => self.send(player::Command::OpenFiles(paths)),
+                    Err(error) => self.show_error(&error),
+                }
             }
         }
 
@@ -217,27 +871,157 @@ impl Application {
             Msg::ShowChapters
         );
 
+        connect!(
+            relm,
+            resources.bookmarks,
+            connect_clicked(_),
+            Msg::ShowBookmarks
+        );
+
+        connect!(
+            relm,
+            resources.add_bookmark,
+            connect_clicked(_),
+            Msg::AddBookmark
+        );
+
+        connect!(relm, resources.rate, connect_clicked(_), Msg::ShowRates);
+
+        connect!(relm, resources.sleep, connect_clicked(_), Msg::ShowSleep);
+
+        connect!(
+            relm,
+            resources.progress,
+            connect_button_press_event(_, _),
+            return (Some(Msg::ScrubStart), Inhibit(false))
+        );
+
+        connect!(
+            relm,
+            resources.progress,
+            connect_value_changed(scale),
+            Msg::Scrub(scale.get_value())
+        );
+
+        connect!(
+            relm,
+            resources.progress,
+            connect_button_release_event(_, _),
+            return (Some(Msg::ScrubEnd), Inhibit(false))
+        );
+
+        connect!(
+            relm,
+            resources.whole_book,
+            connect_toggled(_),
+            Msg::ToggleWholeBook
+        );
+
         connect!(relm, resources.open, connect_clicked(_), Msg::Open);
+
+        connect!(
+            relm,
+            resources.message_bar,
+            connect_response(_, _),
+            Msg::DismissMessage
+        );
+
+        connect!(
+            relm,
+            resources.preferences,
+            connect_clicked(_),
+            Msg::ShowPreferences
+        );
+
+        connect!(relm, resources.library, connect_clicked(_), Msg::ShowLibrary);
+
+        let library = &self.library.resources;
+        connect!(
+            relm,
+            library.search,
+            connect_search_changed(_),
+            Msg::RefreshLibrary
+        );
+
+        connect!(relm, library.sort, connect_changed(_), Msg::RefreshLibrary);
+
+        connect!(relm, library.rescan, connect_clicked(_), Msg::RescanLibrary);
+
+        connect!(
+            relm,
+            library.books,
+            connect_child_activated(_, child),
+            Msg::OpenFromLibrary(child.get_index())
+        );
+    }
+
+    fn remote(&mut self, command: mpris::Command) {
+        use self::mpris::Command;
+
+        match command {
+            Command::Raise => self.resources.view.present(),
+            Command::Quit => self.quit(),
+            Command::Play => self.send(player::Command::Play),
+            Command::Pause | Command::Stop => self.send(player::Command::Pause),
+            Command::PlayPause => self.toggle_play(),
+            Command::Next => self.next_chapter(),
+            Command::Previous => self.previous_chaper(),
+            Command::SeekForward(delta) => self.seek(SeekDirection::Forward(delta)),
+            Command::SeekBackward(delta) => self.seek(SeekDirection::Backward(delta)),
+            Command::SetPosition(position) => self.seek(SeekDirection::At(position)),
+            Command::SetRate(rate) => self.send(player::Command::SetRate(rate)),
+            Command::OpenUri(uri) => self.send(player::Command::OpenUri(uri)),
+        }
     }
 
-    fn build_player(relm: &Relm<Self>) -> player::Player {
-        let (tx, events) = mpsc::channel();
-        let player = player::Player::new(tx);
+    fn quit(&mut self) {
+        // Queries are answered in order, so once the position comes back the
+        // stop before it has saved the book.
+        self.send(player::Command::Stop);
+        player::ask(&self.player, player::Command::Position);
+        self.send(player::Command::Quit);
+        gtk::main_quit();
+    }
+
+    fn send(&self, command: player::Command) {
+        self.player.send(command).is_ok();
+    }
+
+    fn book_id(&self) -> Option<String> {
+        player::ask(&self.player, player::Command::BookId).and_then(|book| book)
+    }
+
+    fn build_mpris(relm: &Relm<Self>) -> Option<mpris::Mpris> {
+        let (tx, commands) = mpsc::channel();
+        let stream = relm.stream().clone();
+
+        let (_channel, sender) = Channel::new(move |command| {
+            stream.emit(Msg::Remote(command));
+        });
+
+        thread::spawn(move || {
+            while let Ok(command) = commands.recv() {
+                sender.send(command).is_ok();
+            }
+        });
+
+        mpris::Mpris::spawn(mpris::Bus::Session, tx).ok()
+    }
+
+    fn build_player(relm: &Relm<Self>) -> mpsc::Sender<player::Command> {
+        let (commands, events) = player::Player::actor();
         let stream = relm.stream().clone();
 
         let (_channel, sender) = Channel::new(move |event| {
             stream.emit(Msg::PlayerEvent(event));
         });
 
-        thread::spawn(move || loop {
-            match events.recv() {
-                Ok(event) => {
-                    sender.send(event).is_ok();
-                }
-                Err(_) => {}
-            };
+        thread::spawn(move || {
+            while let Ok(event) = events.recv() {
+                sender.send(event).is_ok();
+            }
         });
-        player
+        commands
     }
 }
 
@@ -252,22 +1036,74 @@ impl Update for Application {
 
     fn update(&mut self, event: Msg) {
         match event {
-            Msg::Quit => gtk::main_quit(),
+            Msg::Quit => self.quit(),
             Msg::Open => self.open(),
+            Msg::ShowPreferences => self.show_preferences(),
             Msg::TogglePlay => self.toggle_play(),
-            Msg::SkipForward => self.seek(SeekDirection::Forward(time::Duration::from_secs(10))),
-            Msg::SkipBackward => self.seek(SeekDirection::Backward(time::Duration::from_secs(10))),
+            Msg::SkipForward => {
+                let delta = self.settings.skip_forward();
+                self.seek(SeekDirection::Forward(delta))
+            }
+            Msg::SkipBackward => {
+                let delta = self.settings.skip_backward();
+                self.seek(SeekDirection::Backward(delta))
+            }
+            Msg::ScrubStart => self.start_scrub(),
+            Msg::Scrub(fraction) => self.scrub(fraction),
+            Msg::ScrubEnd => self.end_scrub(),
+            Msg::ToggleWholeBook => self.toggle_whole_book(),
             Msg::NextChapter => self.next_chapter(),
             Msg::PreviousChaper => self.previous_chaper(),
-            Msg::ShowChapters => {
-                self.chapters.popup();
+            Msg::ShowChapters => self.show_chapters(),
+            Msg::JumpToChapter(index) => self.jump_to_chapter(index),
+            Msg::SetChapterLevel(level) => self.set_chapter_level(level),
+            Msg::ShowBookmarks => {
+                self.bookmarks.popup();
+            }
+            Msg::AddBookmark => self.add_bookmark(),
+            Msg::JumpToBookmark(id) => self.jump_to_bookmark(id),
+            Msg::EditBookmark(id) => self.edit_bookmark(id),
+            Msg::DeleteBookmark(id) => self.delete_bookmark(id),
+            Msg::ShowRates => {
+                self.rates.popup();
             }
+            Msg::SetRate(rate) => self.send(player::Command::SetRate(rate)),
+            Msg::ShowSleep => {
+                self.sleep.popup();
+            }
+            Msg::SleepAfter(minutes) => {
+                self.start_sleep(player::SleepMode::After(time::Duration::from_secs(minutes * 60)))
+            }
+            Msg::SleepAtChapterEnd => self.start_sleep(player::SleepMode::EndOfChapter),
+            Msg::ExtendSleep => {
+                let by = time::Duration::from_secs(self.settings.sleep.extension_minutes * 60);
+                self.send(player::Command::ExtendSleepTimer(by))
+            }
+            Msg::CancelSleep => self.send(player::Command::CancelSleepTimer),
+            Msg::Remote(command) => self.remote(command),
+            Msg::DismissMessage => self.resources.message_bar.hide(),
+            Msg::ShowLibrary => self.show_library(),
+            Msg::RefreshLibrary => self.library.refresh(),
+            Msg::RescanLibrary => self.rescan_library(),
+            Msg::LibraryScanned(books) => self.reflect_on_scan(books),
+            Msg::OpenFromLibrary(index) => self.open_from_library(index),
             Msg::PlayerEvent(event) => {
+                if let Some(ref mpris) = self.mpris {
+                    mpris.handle(&event);
+                }
+
                 use self::player::Event::*;
                 match event {
                     MetadataChanged(metadata) => self.switch_book(metadata),
                     StateChanged(state) => self.reflect_on_state(state),
                     Progress(clock) => self.update_progress(clock),
+                    RateChanged(rate) => self.reflect_on_rate(rate),
+                    SleepCountdown(remaining) => self.reflect_on_sleep(remaining),
+                    Error(error) => self.show_error(&error),
+                    EndOfStream => self.reflect_on_end(),
+                    BufferingChanged(percent) => self.reflect_on_buffering(percent),
+                    DurationChanged(duration) => self.reflect_on_duration(duration),
+                    Warning(problem) => self.show_warning(&problem),
                 };
             }
         }
@@ -286,21 +1122,78 @@ impl Widget for Application {
 
         resources.view.show_all();
         let chapters = gtk::Popover::new(Some(&resources.chapter));
+        let (chapter_tree, chapter_store, chapter_levels) = Self::build_chapters(relm, &chapters);
+        let bookmarks = gtk::Popover::new(Some(&resources.bookmarks));
+        let rates = gtk::Popover::new(Some(&resources.rate));
+        Self::build_rates(relm, &rates);
+        let sleep = gtk::Popover::new(Some(&resources.sleep));
+
+        let library = LibraryView::new(&resources.view);
 
         let app = Application {
+            relm: relm.clone(),
             player: Self::build_player(relm),
             resources: resources,
             state: player::State::Stopped,
             metadata: Default::default(),
             position: time::Duration::from_secs(0),
+            rate: 1.0,
+            whole_book: false,
+            scrub: None,
             chapters,
+            chapter_tree,
+            chapter_store,
+            chapter_iters: vec![],
+            chapter_levels,
+            chapter_level: 0,
+            chapter: None,
+            bookmarks,
+            bookmark_store: Bookmarks::open(),
+            rates,
+            sleep,
+            mpris: Self::build_mpris(relm),
+            library,
+            settings: Settings::open(),
         };
 
         app.connect(relm);
+        app.reflect_on_settings();
+        if let Some(problem) = app.bookmark_store.problem() {
+            app.show_warning(problem);
+        }
+        if let Some(problem) = app.settings.problem() {
+            app.show_warning(problem);
+        }
         app
     }
 }
 
+/// Local paths of the chosen files. A file on a remote location is reported
+/// rather than quietly left out of the book.
+fn local_paths(files: &[gio::File]) -> ::std::result::Result<Vec<path::PathBuf>, player::PlaybackError> {
+    files
+        .iter()
+        .map(|file| {
+            uri::from_file(file).map_err(|err| player::PlaybackError::Open {
+                path: path::PathBuf::from(file.get_uri()),
+                reason: err.to_string(),
+            })
+        })
+        .collect()
+}
+
+/// Index of the chapter in a row of the chapter tree, -1 for none.
+fn chapter_index(tree: &gtk::TreeView, path: &gtk::TreePath) -> i32 {
+    tree.get_model()
+        .and_then(|model| {
+            model
+                .get_iter(path)
+                .map(|iter| model.get_value(&iter, CHAPTER_INDEX_COLUMN))
+        })
+        .and_then(|value| value.get::<u32>())
+        .map_or(-1, |index| index as i32)
+}
+
 pub fn run() -> Result<()> {
     if let Err(_) = Application::run(()) {
         bail!("application run error");
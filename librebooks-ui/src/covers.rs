@@ -0,0 +1,96 @@
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path;
+use std::time;
+
+use gdk_pixbuf::{InterpType, Pixbuf, PixbufLoader, PixbufLoaderExt};
+
+use core::player::Artwork;
+
+/// Scaled cover for a book, read from the thumbnail cache or made from the
+/// full image and stored there for the next time. The cache is keyed by the
+/// image itself, so a changed cover gets a new thumbnail.
+pub fn thumbnail(book: &str, artwork: &Artwork, size: i32) -> Option<Pixbuf> {
+    let mut hasher = DefaultHasher::new();
+    artwork.data.hash(&mut hasher);
+    cached_or_scaled(book, hasher.finish(), size, || Some(artwork.data.clone()))
+}
+
+/// Same as `thumbnail`, for a cover that lives in a file. The file's size
+/// and modification time stand in for its contents, so that it is only read
+/// when it changed.
+pub fn thumbnail_from_file(book: &str, cover: &path::Path, size: i32) -> Option<Pixbuf> {
+    let stat = fs::metadata(cover).ok()?;
+    let modified = stat
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(time::UNIX_EPOCH).ok())
+        .unwrap_or_default();
+
+    let mut hasher = DefaultHasher::new();
+    (stat.len(), modified).hash(&mut hasher);
+    cached_or_scaled(book, hasher.finish(), size, || fs::read(cover).ok())
+}
+
+fn cached_or_scaled<F>(book: &str, version: u64, size: i32, data: F) -> Option<Pixbuf>
+where
+    F: FnOnce() -> Option<Vec<u8>>,
+{
+    let cached = cache_path(book, version, size)?;
+    if let Ok(pixbuf) = Pixbuf::new_from_file(&cached) {
+        return Some(pixbuf);
+    }
+
+    let loader = PixbufLoader::new();
+    loader.write(&data()?).ok()?;
+    loader.close().ok()?;
+    let pixbuf = scale(&loader.get_pixbuf()?, size)?;
+
+    if let Some(dir) = cached.parent() {
+        fs::create_dir_all(dir).is_ok();
+        remove_stale(dir, book);
+    }
+    pixbuf.savev(&cached, "png", &[]).is_ok();
+    Some(pixbuf)
+}
+
+fn scale(pixbuf: &Pixbuf, size: i32) -> Option<Pixbuf> {
+    let (width, height) = (pixbuf.get_width(), pixbuf.get_height());
+    if width <= 0 || height <= 0 {
+        return None;
+    }
+    let factor = size as f64 / width.max(height) as f64;
+    pixbuf.scale_simple(
+        (width as f64 * factor).round().max(1.0) as i32,
+        (height as f64 * factor).round().max(1.0) as i32,
+        InterpType::Bilinear,
+    )
+}
+
+fn cache_path(book: &str, version: u64, size: i32) -> Option<path::PathBuf> {
+    Some(
+        glib::get_user_cache_dir()?
+            .join("librebooks")
+            .join("thumbnails")
+            .join(size.to_string())
+            .join(format!("{}-{:016x}.png", book, version)),
+    )
+}
+
+/// Drops thumbnails made from earlier covers of a book, including those
+/// named after the book alone before covers were told apart.
+fn remove_stale(dir: &path::Path, book: &str) {
+    let prefix = format!("{}-", book);
+    let unversioned = format!("{}.png", book);
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with(&prefix) || name == unversioned {
+            fs::remove_file(entry.path()).is_ok();
+        }
+    }
+}
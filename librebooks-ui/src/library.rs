@@ -0,0 +1,214 @@
+use std::cmp;
+use std::collections::HashMap;
+use std::sync::mpsc;
+use std::time;
+
+use gtk::prelude::*;
+
+use core::library::{Entry, Library};
+use core::player;
+use covers;
+use resources;
+
+const COVER_SIZE: i32 = 128;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Sort {
+    Recent,
+    Title,
+    Author,
+    Progress,
+}
+
+impl Sort {
+    fn from_id(id: &str) -> Sort {
+        match id {
+            "title" => Sort::Title,
+            "author" => Sort::Author,
+            "progress" => Sort::Progress,
+            _ => Sort::Recent,
+        }
+    }
+}
+
+struct Shelved {
+    entry: Entry,
+    progress: f64,
+    played: time::SystemTime,
+}
+
+/// Browser of the scanned library. Keeps the books in the order they are
+/// shown, so an activated child can be mapped back to its book.
+pub struct LibraryView {
+    pub resources: resources::LibraryWindow,
+    books: Vec<Entry>,
+    shown: Vec<Entry>,
+    records: HashMap<String, player::Record>,
+}
+
+impl LibraryView {
+    pub fn new(parent: &gtk::ApplicationWindow) -> LibraryView {
+        let resources = resources::LibraryWindow::new();
+        resources.view.set_transient_for(Some(parent));
+        resources
+            .view
+            .connect_delete_event(|view, _| Inhibit(view.hide_on_delete()));
+
+        LibraryView {
+            resources,
+            books: Library::open().books().to_vec(),
+            shown: vec![],
+            records: HashMap::new(),
+        }
+    }
+
+    pub fn set_books(&mut self, books: Vec<Entry>) {
+        self.books = books;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.books.is_empty()
+    }
+
+    pub fn book_at(&self, index: i32) -> Option<&Entry> {
+        if index < 0 {
+            return None;
+        }
+        self.shown.get(index as usize)
+    }
+
+    /// Asks the player for the progress of every book in one go, kept until
+    /// asked again so searching does not wait on the player.
+    pub fn load_records(&mut self, commands: &mpsc::Sender<player::Command>) {
+        self.records = player::ask(commands, player::Command::Records).unwrap_or_default();
+    }
+
+    /// Rebuilds the grid from the catalog, applying the search and sort
+    /// currently picked in the header bar.
+    pub fn refresh(&mut self) {
+        let query = self
+            .resources
+            .search
+            .get_text()
+            .unwrap_or_default()
+            .to_lowercase();
+        let sort = self
+            .resources
+            .sort
+            .get_active_id()
+            .map(|id| Sort::from_id(&id))
+            .unwrap_or(Sort::Recent);
+
+        let mut shelved: Vec<Shelved> = self
+            .books
+            .iter()
+            .filter(|entry| {
+                query.is_empty()
+                    || entry.metadata.title.to_lowercase().contains(&query)
+                    || entry.metadata.artist.to_lowercase().contains(&query)
+                    || entry.metadata.narrator.to_lowercase().contains(&query)
+            })
+            .map(|entry| {
+                let record = self.records.get(&entry.id);
+                Shelved {
+                    entry: entry.clone(),
+                    progress: record
+                        .map(|record| fraction(record.position, entry.metadata.duration))
+                        .unwrap_or(0.0),
+                    played: record
+                        .map(|record| record.updated)
+                        .unwrap_or(time::UNIX_EPOCH),
+                }
+            })
+            .collect();
+
+        shelved.sort_by(|a, b| match sort {
+            Sort::Recent => b.played.cmp(&a.played),
+            Sort::Title => key(&a.entry.metadata.title).cmp(&key(&b.entry.metadata.title)),
+            Sort::Author => key(&a.entry.metadata.artist).cmp(&key(&b.entry.metadata.artist)),
+            Sort::Progress => b
+                .progress
+                .partial_cmp(&a.progress)
+                .unwrap_or(cmp::Ordering::Equal),
+        });
+
+        let books = &self.resources.books;
+        for child in books.get_children().iter() {
+            books.remove(child);
+        }
+        for book in shelved.iter() {
+            books.add(&card(book));
+        }
+        books.show_all();
+
+        self.shown = shelved.into_iter().map(|book| book.entry).collect();
+    }
+}
+
+fn card(book: &Shelved) -> gtk::Box {
+    let metadata = &book.entry.metadata;
+    let card = gtk::Box::new(gtk::Orientation::Vertical, 3);
+    card.set_size_request(160, -1);
+
+    let thumbnail = book
+        .entry
+        .cover
+        .as_ref()
+        .and_then(|cover| covers::thumbnail_from_file(&book.entry.id, cover, COVER_SIZE));
+    let cover = match thumbnail {
+        Some(thumbnail) => gtk::Image::new_from_pixbuf(Some(&thumbnail)),
+        None => {
+            let cover =
+                gtk::Image::new_from_icon_name("audio-x-generic", gtk::IconSize::Dialog.into());
+            cover.set_pixel_size(COVER_SIZE);
+            cover
+        }
+    };
+    card.pack_start(&cover, false, true, 6);
+
+    let title = gtk::Label::new(Some(metadata.title.as_str()));
+    title.set_ellipsize(pango::EllipsizeMode::End);
+    title.set_max_width_chars(20);
+    if let Some(context) = title.get_style_context() {
+        context.add_class("heading");
+    }
+    card.pack_start(&title, false, true, 0);
+
+    let author = gtk::Label::new(Some(metadata.artist.as_str()));
+    author.set_ellipsize(pango::EllipsizeMode::End);
+    author.set_max_width_chars(20);
+    if let Some(context) = author.get_style_context() {
+        context.add_class("dim-label");
+    }
+    card.pack_start(&author, false, true, 0);
+
+    let details = gtk::Label::new(Some(
+        format!(
+            "{} · {}%",
+            hours(metadata.duration),
+            (book.progress * 100.0).round()
+        ).as_str(),
+    ));
+    if let Some(context) = details.get_style_context() {
+        context.add_class("dim-label");
+    }
+    card.pack_start(&details, false, true, 0);
+
+    card
+}
+
+fn key(text: &str) -> String {
+    text.to_lowercase()
+}
+
+fn fraction(position: time::Duration, duration: time::Duration) -> f64 {
+    if duration.as_secs() == 0 {
+        return 0.0;
+    }
+    (position.as_secs() as f64 / duration.as_secs() as f64).min(1.0)
+}
+
+fn hours(duration: time::Duration) -> String {
+    let minutes = duration.as_secs() / 60;
+    format!("{}h {:02}m", minutes / 60, minutes % 60)
+}
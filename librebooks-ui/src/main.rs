@@ -4,9 +4,11 @@
 #[macro_use]
 extern crate error_chain;
 extern crate chrono;
+extern crate gdk_pixbuf;
 extern crate gio;
 extern crate glib;
 extern crate gtk;
+extern crate pango;
 #[macro_use]
 extern crate relm;
 extern crate relm_attributes;
@@ -18,6 +20,9 @@ extern crate librebooks_core as core;
 mod macros;
 
 mod app;
+mod covers;
+mod library;
+mod preferences;
 mod errors;
 
 use errors::Result;
@@ -0,0 +1,247 @@
+use std::cell::RefCell;
+use std::path;
+use std::rc::Rc;
+
+use glib::translate::FromGlib;
+use gtk::prelude::*;
+
+use app::RATES;
+use core::settings::{Resume, Theme};
+use core::Settings;
+
+const THEMES: &[(Theme, &str, &str)] = &[
+    (Theme::System, "system", "Follow the system"),
+    (Theme::Light, "light", "Light"),
+    (Theme::Dark, "dark", "Dark"),
+];
+
+const RESUMES: &[(Resume, &str, &str)] = &[
+    (Resume::Position, "position", "Where I left off"),
+    (Resume::ChapterStart, "chapter-start", "At the start of the chapter"),
+    (Resume::Beginning, "beginning", "From the beginning"),
+];
+
+/// Applies the theme preference to the running application.
+pub fn apply_theme(theme: Theme) {
+    if let Some(settings) = gtk::Settings::get_default() {
+        settings.set_property_gtk_application_prefer_dark_theme(theme == Theme::Dark);
+    }
+}
+
+/// Shows the Preferences dialog and returns the edited settings, or `None`
+/// when the dialog was cancelled.
+pub fn run(parent: &gtk::ApplicationWindow, settings: &Settings) -> Option<Settings> {
+    let dialog = gtk::Dialog::new_with_buttons(
+        Some("Preferences"),
+        Some(parent),
+        gtk::DialogFlags::MODAL,
+        &[
+            ("Cancel", gtk::ResponseType::Cancel),
+            ("Save", gtk::ResponseType::Accept),
+        ],
+    );
+
+    let grid = gtk::Grid::new();
+    grid.set_row_spacing(6);
+    grid.set_column_spacing(12);
+    grid.set_border_width(12);
+    let mut row = 0;
+
+    heading(&grid, &mut row, "Playback");
+    let skip_backward = spin(0.0, 300.0, settings.playback.skip_backward_secs as f64);
+    field(&grid, &mut row, "Skip back (seconds)", &skip_backward);
+    let skip_forward = spin(0.0, 300.0, settings.playback.skip_forward_secs as f64);
+    field(&grid, &mut row, "Skip forward (seconds)", &skip_forward);
+
+    let default_rate = gtk::ComboBoxText::new();
+    for rate in RATES {
+        default_rate.append(Some(rate.to_string().as_str()), &format!("{}×", rate));
+    }
+    default_rate.set_active_id(Some(settings.playback.default_rate.to_string().as_str()));
+    field(&grid, &mut row, "Speed of new books", &default_rate);
+
+    let resume = choice(RESUMES, settings.playback.resume);
+    field(&grid, &mut row, "Resume books", &resume);
+
+    let smart_rewind = gtk::CheckButton::new_with_label("Rewind a little after a pause");
+    smart_rewind.set_active(settings.playback.smart_rewind);
+    field(&grid, &mut row, "", &smart_rewind);
+
+    heading(&grid, &mut row, "Library");
+    let folders = Rc::new(RefCell::new(settings.library.folders.clone()));
+    let folder_list = gtk::ListBox::new();
+    fill_folders(&folder_list, &folders.borrow());
+    field(&grid, &mut row, "Folders", &folder_list);
+
+    let folder_buttons = gtk::Box::new(gtk::Orientation::Horizontal, 6);
+    let add_folder = gtk::Button::new_with_label("Add…");
+    let remove_folder = gtk::Button::new_with_label("Remove");
+    folder_buttons.pack_start(&add_folder, false, true, 0);
+    folder_buttons.pack_start(&remove_folder, false, true, 0);
+    field(&grid, &mut row, "", &folder_buttons);
+
+    {
+        let (folders, folder_list, dialog) = (folders.clone(), folder_list.clone(), dialog.clone());
+        add_folder.connect_clicked(move |_| {
+            if let Some(folder) = choose_folder(&dialog) {
+                folders.borrow_mut().push(folder);
+                fill_folders(&folder_list, &folders.borrow());
+            }
+        });
+    }
+    {
+        let (folders, folder_list) = (folders.clone(), folder_list.clone());
+        remove_folder.connect_clicked(move |_| {
+            if let Some(row) = folder_list.get_selected_row() {
+                let index = row.get_index();
+                if index >= 0 && (index as usize) < folders.borrow().len() {
+                    folders.borrow_mut().remove(index as usize);
+                }
+                fill_folders(&folder_list, &folders.borrow());
+            }
+        });
+    }
+
+    heading(&grid, &mut row, "Sleep timer");
+    let sleep_minutes = gtk::Entry::new();
+    sleep_minutes.set_text(&join(&settings.sleep.minutes));
+    sleep_minutes.set_tooltip_text(Some("Minutes, separated by commas"));
+    field(&grid, &mut row, "Durations (minutes)", &sleep_minutes);
+    let extension = spin(1.0, 60.0, settings.sleep.extension_minutes as f64);
+    field(&grid, &mut row, "Extend by (minutes)", &extension);
+    let fade = spin(0.0, 120.0, settings.sleep.fade_secs as f64);
+    field(&grid, &mut row, "Fade out (seconds)", &fade);
+    let reset = gtk::CheckButton::new_with_label("Restart the timer when I use the controls");
+    reset.set_active(settings.sleep.reset_on_interaction);
+    field(&grid, &mut row, "", &reset);
+
+    heading(&grid, &mut row, "Appearance");
+    let theme = choice(THEMES, settings.appearance.theme);
+    field(&grid, &mut row, "Theme", &theme);
+
+    let content = dialog.get_content_area();
+    content.pack_start(&grid, true, true, 0);
+    content.show_all();
+
+    let mut edited = None;
+    if gtk::ResponseType::from_glib(dialog.run()) == gtk::ResponseType::Accept {
+        let mut settings = settings.clone();
+
+        settings.playback.skip_backward_secs = skip_backward.get_value_as_int() as u64;
+        settings.playback.skip_forward_secs = skip_forward.get_value_as_int() as u64;
+        if let Some(rate) = default_rate.get_active_id().and_then(|id| id.parse().ok()) {
+            settings.playback.default_rate = rate;
+        }
+        settings.playback.resume = chosen(RESUMES, &resume).unwrap_or(settings.playback.resume);
+        settings.playback.smart_rewind = smart_rewind.get_active();
+
+        settings.library.folders = folders.borrow().clone();
+
+        let minutes = parse_minutes(&sleep_minutes.get_text().unwrap_or_default());
+        if !minutes.is_empty() {
+            settings.sleep.minutes = minutes;
+        }
+        settings.sleep.extension_minutes = extension.get_value_as_int() as u64;
+        settings.sleep.fade_secs = fade.get_value_as_int() as u64;
+        settings.sleep.reset_on_interaction = reset.get_active();
+
+        settings.appearance.theme = chosen(THEMES, &theme).unwrap_or(settings.appearance.theme);
+
+        edited = Some(settings);
+    }
+
+    dialog.close();
+    edited
+}
+
+fn heading(grid: &gtk::Grid, row: &mut i32, text: &str) {
+    let label = gtk::Label::new(None);
+    label.set_markup(&format!("<b>{}</b>", text));
+    label.set_halign(gtk::Align::Start);
+    if *row > 0 {
+        label.set_margin_top(12);
+    }
+    grid.attach(&label, 0, *row, 2, 1);
+    *row += 1;
+}
+
+fn field<W: IsA<gtk::Widget>>(grid: &gtk::Grid, row: &mut i32, text: &str, widget: &W) {
+    let label = gtk::Label::new(Some(text));
+    label.set_halign(gtk::Align::End);
+    label.set_valign(gtk::Align::Start);
+    grid.attach(&label, 0, *row, 1, 1);
+    grid.attach(widget, 1, *row, 1, 1);
+    *row += 1;
+}
+
+fn spin(min: f64, max: f64, value: f64) -> gtk::SpinButton {
+    let spin = gtk::SpinButton::new_with_range(min, max, 1.0);
+    spin.set_value(value);
+    spin
+}
+
+fn choice<T: PartialEq + Copy>(options: &[(T, &str, &str)], current: T) -> gtk::ComboBoxText {
+    let combo = gtk::ComboBoxText::new();
+    for &(value, id, text) in options {
+        combo.append(Some(id), text);
+        if value == current {
+            combo.set_active_id(Some(id));
+        }
+    }
+    combo
+}
+
+fn chosen<T: Copy>(options: &[(T, &str, &str)], combo: &gtk::ComboBoxText) -> Option<T> {
+    let id = combo.get_active_id()?;
+    options
+        .iter()
+        .find(|&&(_, option, _)| option == id)
+        .map(|&(value, _, _)| value)
+}
+
+fn fill_folders(list: &gtk::ListBox, folders: &[path::PathBuf]) {
+    for child in list.get_children().iter() {
+        list.remove(child);
+    }
+    for folder in folders {
+        let label = gtk::Label::new(Some(folder.to_string_lossy().as_ref()));
+        label.set_halign(gtk::Align::Start);
+        list.add(&label);
+    }
+    list.show_all();
+}
+
+fn choose_folder(parent: &gtk::Dialog) -> Option<path::PathBuf> {
+    let chooser = gtk::FileChooserDialog::with_buttons(
+        Some("Add a library folder"),
+        Some(parent),
+        gtk::FileChooserAction::SelectFolder,
+        &[
+            ("Cancel", gtk::ResponseType::Cancel),
+            ("Add", gtk::ResponseType::Accept),
+        ],
+    );
+
+    let folder = if gtk::ResponseType::from_glib(chooser.run()) == gtk::ResponseType::Accept {
+        chooser.get_filename()
+    } else {
+        None
+    };
+    chooser.close();
+    folder
+}
+
+fn join(minutes: &[u64]) -> String {
+    minutes
+        .iter()
+        .map(|minutes| minutes.to_string())
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+fn parse_minutes(text: &str) -> Vec<u64> {
+    text.split(',')
+        .filter_map(|minutes| minutes.trim().parse().ok())
+        .filter(|&minutes| minutes > 0)
+        .collect()
+}
@@ -26,6 +26,14 @@ macro_rules! resource {
 pub struct Resources(gtk::Builder);
 
 impl Resources {
+    fn load(path: &str) -> Resources {
+        let builder = gtk::Builder::new();
+        builder
+            .add_from_resource(resource!(path))
+            .expect(&format!("should add {}", path));
+        Resources(builder)
+    }
+
     pub fn get<T: IsA<Object>>(&self, id: &str) -> T {
         self.0
             .get_object(id)
@@ -44,21 +52,24 @@ pub struct MainWindow {
     pub played: gtk::Label,
     pub remaining: gtk::Label,
     pub open: gtk::Button,
+    pub library: gtk::Button,
+    pub preferences: gtk::Button,
     pub title: gtk::Label,
-    pub progress: gtk::ProgressBar,
+    pub progress: gtk::Scale,
+    pub whole_book: gtk::ToggleButton,
+    pub artwork: gtk::Image,
+    pub message_bar: gtk::InfoBar,
+    pub message: gtk::Label,
     pub chapter: gtk::Button,
+    pub bookmarks: gtk::Button,
+    pub add_bookmark: gtk::Button,
+    pub rate: gtk::Button,
+    pub sleep: gtk::Button,
 }
 
 impl MainWindow {
     pub fn new() -> MainWindow {
-        let resources = Resources({
-            let builder = gtk::Builder::new();
-
-            builder
-                .add_from_resource(resource!("ui/main_window.ui"))
-                .expect("should add ui/main_window.ui");
-            builder
-        });
+        let resources = Resources::load("ui/main_window.ui");
 
         MainWindow {
             view: resources.get("main-window"),
@@ -68,11 +79,44 @@ impl MainWindow {
             next_chapter: resources.get("next-chapter"),
             previous_chapter: resources.get("previous-chapter"),
             open: resources.get("open"),
+            library: resources.get("library"),
+            preferences: resources.get("preferences"),
             played: resources.get("played"),
             remaining: resources.get("remaining"),
             title: resources.get("title"),
             progress: resources.get("progress"),
+            whole_book: resources.get("whole-book"),
+            artwork: resources.get("artwork"),
+            message_bar: resources.get("message-bar"),
+            message: resources.get("message"),
             chapter: resources.get("chapter"),
+            bookmarks: resources.get("bookmarks"),
+            add_bookmark: resources.get("add-bookmark"),
+            rate: resources.get("rate"),
+            sleep: resources.get("sleep"),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct LibraryWindow {
+    pub view: gtk::Window,
+    pub books: gtk::FlowBox,
+    pub search: gtk::SearchEntry,
+    pub sort: gtk::ComboBoxText,
+    pub rescan: gtk::Button,
+}
+
+impl LibraryWindow {
+    pub fn new() -> LibraryWindow {
+        let resources = Resources::load("ui/library_window.ui");
+
+        LibraryWindow {
+            view: resources.get("library-window"),
+            books: resources.get("library-books"),
+            search: resources.get("library-search"),
+            sort: resources.get("library-sort"),
+            rescan: resources.get("library-rescan"),
         }
     }
 }